@@ -0,0 +1,146 @@
+//! Request bodies, kept in memory while small and spilled to a temporary file
+//! once they grow larger, like git's `http.postBuffer`.
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+#[cfg(feature = "gzip")]
+use flate2::write::GzEncoder;
+#[cfg(feature = "gzip")]
+use flate2::Compression;
+use ureq::{Request, Response};
+
+/// The size up to which bodies are kept in memory, unless configured otherwise.
+pub(crate) const DEFAULT_POST_BUFFER: usize = 1024 * 1024;
+
+/// The smallest `http.postBuffer` git accepts, the size of the largest pkt-line.
+pub(crate) const MIN_POST_BUFFER: usize = 65520;
+
+#[derive(Default)]
+pub(crate) enum Body {
+    #[default]
+    Empty,
+    Memory(Vec<u8>),
+    File {
+        file: File,
+        len: u64,
+    },
+}
+
+impl Body {
+    /// Append `data` to the body, spilling it to a temporary file when it grows
+    /// larger than `post_buffer` bytes.
+    pub(crate) fn write(&mut self, data: &[u8], post_buffer: usize) -> io::Result<()> {
+        match self {
+            Body::Empty => *self = Body::Memory(data.to_vec()),
+            Body::Memory(buffer) if buffer.len() + data.len() > post_buffer => {
+                let mut file = tempfile::tempfile()?;
+                file.write_all(buffer)?;
+                file.write_all(data)?;
+                let len = (buffer.len() + data.len()) as u64;
+                *self = Body::File { file, len };
+            }
+            Body::Memory(buffer) => buffer.extend_from_slice(data),
+            Body::File { file, len } => {
+                file.write_all(data)?;
+                *len += data.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// The size of the body in bytes.
+    pub(crate) fn len(&self) -> u64 {
+        match self {
+            Body::Empty => 0,
+            Body::Memory(buffer) => buffer.len() as u64,
+            Body::File { len, .. } => *len,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        match self {
+            Body::Empty => true,
+            Body::Memory(buffer) => buffer.is_empty(),
+            Body::File { len, .. } => *len == 0,
+        }
+    }
+
+    /// The contents of the body.
+    #[cfg(feature = "dumb-http")]
+    pub(crate) fn to_vec(&self) -> io::Result<Vec<u8>> {
+        match self {
+            Body::Empty => Ok(Vec::new()),
+            Body::Memory(buffer) => Ok(buffer.clone()),
+            Body::File { file, len } => {
+                let mut file = file.try_clone()?;
+                file.seek(SeekFrom::Start(0))?;
+                let mut buffer = Vec::new();
+                file.take(*len).read_to_end(&mut buffer)?;
+                Ok(buffer)
+            }
+        }
+    }
+
+    /// The body compressed with gzip, spilled to a temporary file when it grows
+    /// larger than `post_buffer` bytes.
+    #[cfg(feature = "gzip")]
+    pub(crate) fn gzip(&self, post_buffer: usize) -> io::Result<Body> {
+        let compressed = Spilling {
+            body: Body::Empty,
+            post_buffer,
+        };
+        let mut encoder = GzEncoder::new(compressed, Compression::default());
+        match self {
+            Body::Empty => {}
+            Body::Memory(buffer) => encoder.write_all(buffer)?,
+            Body::File { file, len } => {
+                let mut file = file.try_clone()?;
+                file.seek(SeekFrom::Start(0))?;
+                io::copy(&mut file.take(*len), &mut encoder)?;
+            }
+        }
+        Ok(encoder.finish()?.body)
+    }
+
+    /// Send `request` with this body.
+    ///
+    /// Bodies kept in memory are sent with an explicit `Content-Length`, as some
+    /// servers reject chunked requests, spilled ones are streamed from their file
+    /// with chunked encoding. The body can be sent again.
+    #[allow(clippy::result_large_err)] // the error of ureq, as returned by `Request::send`
+    pub(crate) fn send(&self, request: Request) -> Result<Response, ureq::Error> {
+        match self {
+            Body::Empty => request.call(),
+            Body::Memory(buffer) => request
+                .set("Content-Length", &buffer.len().to_string())
+                .send_bytes(buffer),
+            Body::File { file, len } => {
+                let mut file = file.try_clone()?;
+                file.seek(SeekFrom::Start(0))?;
+                request.send(file.take(*len))
+            }
+        }
+    }
+}
+
+/// A body written through [`Write`], spilling to a temporary file like [`Body::write`].
+#[cfg(feature = "gzip")]
+struct Spilling {
+    body: Body,
+    post_buffer: usize,
+}
+
+#[cfg(feature = "gzip")]
+impl Write for Spilling {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.body.write(data, self.post_buffer)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
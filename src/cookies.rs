@@ -0,0 +1,364 @@
+//! Cookies set by servers and sent back with the following requests, like
+//! curl does when git's `http.cookieFile` is set.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use url::Url;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Cookie {
+    name: String,
+    value: String,
+    /// Lowercase, without a leading `.`.
+    domain: String,
+    /// Whether `domain` only matches itself, not its subdomains.
+    host_only: bool,
+    path: String,
+    secure: bool,
+    /// Seconds since the Unix epoch, `None` for session cookies.
+    expires: Option<u64>,
+}
+
+impl Cookie {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Whether the cookie is to be sent with requests to `url`.
+    fn matches(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let host = host.to_ascii_lowercase();
+        let domain_matched = host == self.domain
+            || (!self.host_only && host.ends_with(&format!(".{}", self.domain)));
+
+        domain_matched
+            && path_matches(url.path(), &self.path)
+            && (!self.secure || url.scheme() == "https")
+    }
+}
+
+/// The cookies of a transport, keyed by their name, domain and path as of RFC 6265.
+#[derive(Debug, Default)]
+pub(crate) struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieJar {
+    /// Read the cookies of the Netscape cookie file at `path`.
+    ///
+    /// A file that doesn't exist is read as an empty one.
+    pub(crate) fn load(path: &Path) -> io::Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let now = now();
+        let cookies = contents
+            .lines()
+            .filter_map(parse_line)
+            .filter(|cookie| !cookie.is_expired(now))
+            .collect();
+        Ok(Self { cookies })
+    }
+
+    /// Write the cookies to the Netscape cookie file at `path`.
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        let mut contents = String::from("# Netscape HTTP Cookie File\n");
+        for cookie in &self.cookies {
+            let domain = match cookie.host_only {
+                true => cookie.domain.clone(),
+                false => format!(".{}", cookie.domain),
+            };
+            contents.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                domain,
+                upper_bool(!cookie.host_only),
+                cookie.path,
+                upper_bool(cookie.secure),
+                cookie.expires.unwrap_or(0),
+                cookie.name,
+                cookie.value,
+            ));
+        }
+        fs::write(path, contents)
+    }
+
+    /// Store the cookie of the `Set-Cookie` header `header`, received from `url`.
+    ///
+    /// Returns whether the jar changed. Cookies for other domains than the one of
+    /// `url` are ignored, and expired ones remove the cookie they replace.
+    pub(crate) fn store(&mut self, url: &Url, header: &str) -> bool {
+        let now = now();
+        let Some(cookie) = parse_set_cookie(url, header, now) else {
+            return false;
+        };
+
+        let existing = self.cookies.iter().position(|existing| {
+            existing.name == cookie.name
+                && existing.domain == cookie.domain
+                && existing.path == cookie.path
+        });
+        match existing {
+            Some(index) if cookie.is_expired(now) => {
+                self.cookies.remove(index);
+                true
+            }
+            Some(index) if self.cookies[index] == cookie => false,
+            Some(index) => {
+                self.cookies[index] = cookie;
+                true
+            }
+            None if cookie.is_expired(now) => false,
+            None => {
+                self.cookies.push(cookie);
+                true
+            }
+        }
+    }
+
+    /// The value of the `Cookie` header to send with requests to `url`, if any.
+    ///
+    /// Cookies with longer paths come first.
+    pub(crate) fn header(&self, url: &Url) -> Option<String> {
+        let now = now();
+        let mut cookies: Vec<&Cookie> = self
+            .cookies
+            .iter()
+            .filter(|cookie| !cookie.is_expired(now) && cookie.matches(url))
+            .collect();
+        if cookies.is_empty() {
+            return None;
+        }
+
+        cookies.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        let pairs: Vec<String> = cookies
+            .iter()
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect();
+        Some(pairs.join("; "))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn upper_bool(value: bool) -> &'static str {
+    match value {
+        true => "TRUE",
+        false => "FALSE",
+    }
+}
+
+/// Parse a line of a Netscape cookie file:
+/// `domain`, `include subdomains`, `path`, `secure`, `expires`, `name` and `value`,
+/// separated by tabs.
+fn parse_line(line: &str) -> Option<Cookie> {
+    // curl marks HTTP only cookies with a prefix, other lines starting with `#` are comments
+    let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+    if line.starts_with('#') || line.trim().is_empty() {
+        return None;
+    }
+
+    let fields: Vec<&str> = line.split('\t').collect();
+    let [domain, include_subdomains, path, secure, expires, name, value] = fields[..] else {
+        return None;
+    };
+    let expires = expires.parse::<u64>().ok()?;
+    Some(Cookie {
+        name: name.to_string(),
+        value: value.to_string(),
+        domain: domain.trim_start_matches('.').to_ascii_lowercase(),
+        host_only: !include_subdomains.eq_ignore_ascii_case("TRUE"),
+        path: path.to_string(),
+        secure: secure.eq_ignore_ascii_case("TRUE"),
+        expires: (expires != 0).then_some(expires),
+    })
+}
+
+/// Parse the `Set-Cookie` header `header`, received from `url` at `now`.
+fn parse_set_cookie(url: &Url, header: &str, now: u64) -> Option<Cookie> {
+    let mut parts = header.split(';');
+    let (name, value) = parts.next()?.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    let host = url.host_str()?.to_ascii_lowercase();
+    let mut cookie = Cookie {
+        name: name.to_string(),
+        value: value.trim().to_string(),
+        domain: host.clone(),
+        host_only: true,
+        path: default_path(url.path()),
+        secure: false,
+        expires: None,
+    };
+
+    // Max-Age takes precedence over Expires
+    let mut max_age = None;
+    for attribute in parts {
+        let (key, value) = match attribute.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => (attribute.trim(), ""),
+        };
+        if key.eq_ignore_ascii_case("domain") && !value.is_empty() {
+            let domain = value.trim_start_matches('.').to_ascii_lowercase();
+            if host != domain && !host.ends_with(&format!(".{domain}")) {
+                return None;
+            }
+            // Cookies of a public suffix would be sent to every site under it: only
+            // the host that set one keeps it, as host-only, as RFC 6265 recommends
+            if is_public_suffix(&domain) {
+                if host != domain {
+                    return None;
+                }
+                continue;
+            }
+            cookie.domain = domain;
+            cookie.host_only = false;
+        } else if key.eq_ignore_ascii_case("path") && value.starts_with('/') {
+            cookie.path = value.to_string();
+        } else if key.eq_ignore_ascii_case("secure") {
+            cookie.secure = true;
+        } else if key.eq_ignore_ascii_case("max-age") {
+            max_age = value.parse::<i64>().ok();
+        } else if key.eq_ignore_ascii_case("expires") && cookie.expires.is_none() {
+            cookie.expires = parse_http_date(value);
+        }
+    }
+    if let Some(max_age) = max_age {
+        // A zero or negative Max-Age expires the cookie right away
+        cookie.expires = Some(match u64::try_from(max_age) {
+            Ok(max_age) if max_age > 0 => now.saturating_add(max_age),
+            _ => 0,
+        });
+    }
+
+    Some(cookie)
+}
+
+/// Whether `domain` is a public suffix, such as `com` or `co.uk`, under which
+/// anyone can register a domain.
+fn is_public_suffix(domain: &str) -> bool {
+    psl::suffix_str(domain) == Some(domain)
+}
+
+/// The path of cookies that don't set one: the directory of `path`.
+fn default_path(path: &str) -> String {
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(index) => path[..index].to_string(),
+    }
+}
+
+/// Whether `path` is within the cookie path `cookie_path`.
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || (path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+/// Parse a date such as `Sun, 06 Nov 1994 08:49:37 GMT`, or `Sun, 06-Nov-1994 08:49:37 GMT`,
+/// as seconds since the Unix epoch.
+fn parse_http_date(date: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+
+    let date = date.split_once(',').map_or(date, |(_, date)| date);
+    let fields: Vec<&str> = date
+        .split(|c: char| c.is_whitespace() || c == '-')
+        .filter(|field| !field.is_empty())
+        .collect();
+    let [day, month, year, time, ..] = fields[..] else {
+        return None;
+    };
+
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| {
+        month
+            .get(..3)
+            .is_some_and(|month| month.eq_ignore_ascii_case(name))
+    })? as u64
+        + 1;
+    let mut year: u64 = year.parse().ok()?;
+    if year < 70 {
+        year += 2000;
+    } else if year < 100 {
+        year += 1900;
+    }
+    let mut time = time.split(':').map(|field| field.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+    if year < 1970 || !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    // Days since the epoch of the civil date, from Howard Hinnant's algorithm
+    let (y, m) = match month {
+        1 | 2 => (year - 1, month + 9),
+        _ => (year, month - 3),
+    };
+    let era = y / 400;
+    let year_of_era = y % 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146097 + day_of_era).checked_sub(719468)?;
+
+    Some(days * 86400 + hours * 3600 + minutes * 60 + seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_cookie(url: &str, header: &str) -> Option<Cookie> {
+        parse_set_cookie(&Url::parse(url).unwrap(), header, 0)
+    }
+
+    #[test]
+    fn domain_cookie_matches_subdomains() {
+        let cookie =
+            set_cookie("https://git.example.com/repo", "a=b; Domain=.Example.com").unwrap();
+        assert_eq!(cookie.domain, "example.com");
+        assert!(!cookie.host_only);
+        assert!(cookie.matches(&Url::parse("https://other.example.com/").unwrap()));
+    }
+
+    #[test]
+    fn cookie_of_another_domain_is_ignored() {
+        assert_eq!(
+            set_cookie("https://git.example.com/", "a=b; Domain=example.org"),
+            None
+        );
+    }
+
+    #[test]
+    fn cookie_of_a_public_suffix_is_ignored() {
+        for (url, domain) in [
+            ("https://git.example.com/", "com"),
+            ("https://git.example.co.uk/", "co.uk"),
+            ("https://example.co.uk/", ".CO.UK"),
+        ] {
+            let header = format!("a=b; Domain={domain}");
+            assert_eq!(set_cookie(url, &header), None, "{domain}");
+        }
+    }
+
+    #[test]
+    fn cookie_of_a_public_suffix_set_by_itself_is_host_only() {
+        let cookie = set_cookie("http://localhost/repo", "a=b; Domain=localhost").unwrap();
+        assert_eq!(cookie.domain, "localhost");
+        assert!(cookie.host_only);
+    }
+}
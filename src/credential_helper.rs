@@ -0,0 +1,101 @@
+//! Integration with the credential helpers configured in git config, through
+//! `git credential fill`, `approve` and `reject`.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use log::debug;
+use url::Url;
+
+use crate::git_config;
+
+/// Whether a `credential.helper`, or a `credential.<url>.helper` whose URL matches
+/// `url`, is configured.
+pub(crate) fn configured(url: &Url) -> bool {
+    let config = match git2::Config::open_default() {
+        Ok(config) => config,
+        Err(_) => return false,
+    };
+    let mut entries = match config.entries(Some(r"^credential\..*helper$")) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+
+    let mut configured = false;
+    while let Some(Ok(entry)) = entries.next() {
+        let Some(pattern) = entry
+            .name()
+            .and_then(|name| name.strip_prefix("credential."))
+            .and_then(|name| name.strip_suffix("helper"))
+        else {
+            continue;
+        };
+        let applies = match pattern.strip_suffix('.') {
+            Some(pattern) => git_config::matches(pattern, url).is_some(),
+            None => pattern.is_empty(),
+        };
+        if applies {
+            // An empty value resets the list of helpers
+            configured = entry.value().is_some_and(|value| !value.is_empty());
+        }
+    }
+    configured
+}
+
+/// Ask the credential helpers for the username and password to use for `url`.
+pub(crate) fn fill(url: &str, username: Option<&str>) -> Option<(String, String)> {
+    let output = run("fill", &description(url, username, None))?;
+
+    let mut username = None;
+    let mut password = None;
+    for line in output.lines() {
+        if let Some(value) = line.strip_prefix("username=") {
+            username = Some(value.to_string());
+        } else if let Some(value) = line.strip_prefix("password=") {
+            password = Some(value.to_string());
+        }
+    }
+    Some((username.unwrap_or_default(), password?))
+}
+
+/// Tell the credential helpers that the credentials were accepted by the server.
+pub(crate) fn approve(url: &str, username: &str, password: &str) {
+    run("approve", &description(url, Some(username), Some(password)));
+}
+
+/// Tell the credential helpers that the credentials were rejected by the server.
+pub(crate) fn reject(url: &str, username: &str, password: &str) {
+    run("reject", &description(url, Some(username), Some(password)));
+}
+
+fn description(url: &str, username: Option<&str>, password: Option<&str>) -> String {
+    let mut description = format!("url={url}\n");
+    if let Some(username) = username {
+        description.push_str(&format!("username={username}\n"));
+    }
+    if let Some(password) = password {
+        description.push_str(&format!("password={password}\n"));
+    }
+    description.push('\n');
+    description
+}
+
+fn run(action: &str, input: &str) -> Option<String> {
+    let mut child = Command::new("git")
+        .args(["credential", action])
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| debug!("failed to run `git credential {}`: {}", action, e))
+        .ok()?;
+
+    child.stdin.take()?.write_all(input.as_bytes()).ok()?;
+    let output = child.wait_with_output().ok()?;
+    if !output.status.success() {
+        debug!("`git credential {}` exited with {}", action, output.status);
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
@@ -0,0 +1,802 @@
+//! The dumb HTTP protocol, spoken with servers that only serve the files of a
+//! repository, translated to the smart protocol libgit2 expects.
+//!
+//! The references of `info/refs` are turned into an advertisement, and the
+//! objects the client wants are gathered from loose objects and the packs of
+//! `objects/info/packs` into a single pack, down to the objects it has.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, SeekFrom};
+
+use flate2::bufread;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use git2::{Error, ErrorClass, ErrorCode};
+
+fn err(message: String) -> io::Error {
+    io::Error::other(Error::new(
+        ErrorCode::GenericError,
+        ErrorClass::Net,
+        message,
+    ))
+}
+
+fn pkt_line(out: &mut Vec<u8>, line: &str) {
+    out.extend_from_slice(format!("{:04x}", line.len() + 4).as_bytes());
+    out.extend_from_slice(line.as_bytes());
+}
+
+/// The smart advertisement of `service` for the `info/refs` file `refs`, fetched
+/// from `refs_url`, with `HEAD` pointing to `head`, the contents of the `HEAD`
+/// file, if any.
+///
+/// Fails when a line of `refs` isn't an object id and a reference name separated
+/// by a tab, and for repositories using another object format than SHA-1, whose
+/// packs can't be put together here.
+pub(crate) fn advertisement(
+    service: &str,
+    refs: &str,
+    refs_url: &str,
+    head: Option<&str>,
+) -> io::Result<Vec<u8>> {
+    let mut parsed = Vec::new();
+    for line in refs.lines().filter(|line| !line.trim().is_empty()) {
+        match line.trim_end().split_once('\t') {
+            Some((id, name)) if is_object_id(id) && !name.is_empty() && !name.contains('\t') => {
+                parsed.push((id, name))
+            }
+            // SHA-256 object ids
+            Some((id, _)) if id.len() == 64 && is_hex(id) => {
+                return Err(err(format!(
+                    "{refs_url} is a SHA-256 repository, only SHA-1 repositories can be \
+                     fetched with the dumb HTTP protocol"
+                )))
+            }
+            _ => {
+                return Err(err(format!(
+                    "{refs_url} not valid: is this a git repository?"
+                )))
+            }
+        }
+    }
+    let refs = parsed;
+
+    // `HEAD` is either a symbolic reference to a branch, or detached
+    let head = head.map(str::trim);
+    let (head_id, symref) = match head.and_then(|head| head.strip_prefix("ref: ")) {
+        Some(target) => (
+            refs.iter()
+                .find(|(_, name)| *name == target)
+                .map(|(id, _)| *id),
+            Some(target),
+        ),
+        None => (head.filter(|head| is_object_id(head)), None),
+    };
+    let capabilities = match symref {
+        Some(target) => format!("ofs-delta symref=HEAD:{target}"),
+        None => "ofs-delta".to_string(),
+    };
+
+    let mut out = Vec::new();
+    pkt_line(&mut out, &format!("# service=git-{service}\n"));
+    out.extend_from_slice(b"0000");
+    let mut lines: Vec<(&str, &str)> = head_id.map(|id| (id, "HEAD")).into_iter().collect();
+    lines.extend(refs);
+    match lines.split_first() {
+        Some(((id, name), rest)) => {
+            pkt_line(&mut out, &format!("{id} {name}\0{capabilities}\n"));
+            for (id, name) in rest {
+                pkt_line(&mut out, &format!("{id} {name}\n"));
+            }
+        }
+        None => pkt_line(
+            &mut out,
+            &format!("{} capabilities^{{}}\0{capabilities}\n", "0".repeat(40)),
+        ),
+    }
+    out.extend_from_slice(b"0000");
+    Ok(out)
+}
+
+/// Whether `id` is a SHA-1 object id in hexadecimal.
+fn is_object_id(id: &str) -> bool {
+    id.len() == 40 && is_hex(id)
+}
+
+fn is_hex(s: &str) -> bool {
+    s.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// The lines of an upload-pack request.
+#[derive(Debug, Default)]
+struct Request {
+    wants: Vec<String>,
+    haves: Vec<String>,
+    /// Whether the client is done negotiating.
+    done: bool,
+}
+
+fn parse_request(request: &[u8]) -> io::Result<Request> {
+    let mut parsed = Request::default();
+    let mut rest = request;
+    while rest.len() >= 4 {
+        let len = std::str::from_utf8(&rest[..4])
+            .ok()
+            .and_then(|len| usize::from_str_radix(len, 16).ok())
+            .ok_or_else(|| err("invalid pkt-line in upload-pack request".to_string()))?;
+        if len < 4 {
+            rest = &rest[4..];
+            continue;
+        }
+        let line = rest
+            .get(4..len)
+            .ok_or_else(|| err("truncated pkt-line in upload-pack request".to_string()))?;
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end();
+        if let Some(want) = line.strip_prefix("want ") {
+            parsed
+                .wants
+                .extend(want.split(' ').next().map(str::to_string));
+        } else if let Some(have) = line.strip_prefix("have ") {
+            parsed.haves.push(have.trim().to_string());
+        } else if line == "done" {
+            parsed.done = true;
+        }
+        rest = &rest[len..];
+    }
+    Ok(parsed)
+}
+
+/// A fetch from a dumb server, whose negotiation spans several requests.
+#[derive(Debug, Default)]
+pub(crate) struct Fetch {
+    /// The objects the client said it has so far.
+    haves: HashSet<String>,
+}
+
+impl Fetch {
+    /// Answer the upload-pack request `request`, fetching the files of the repository
+    /// through `get`, which returns `None` for those that don't exist.
+    ///
+    /// No object is ever acknowledged, so that the client keeps listing those it has
+    /// until it is done. The walk from the objects it wants then stops at them: only
+    /// the objects reachable from those it has through the ones it lacks are sent again.
+    pub(crate) fn upload_pack(
+        &mut self,
+        request: &[u8],
+        get: impl FnMut(&str) -> io::Result<Option<File>>,
+    ) -> io::Result<Box<dyn Read + Send>> {
+        let request = parse_request(request)?;
+        self.haves.extend(request.haves);
+        let mut nak = Vec::new();
+        pkt_line(&mut nak, "NAK\n");
+        if !request.done {
+            return Ok(Box::new(io::Cursor::new(nak)));
+        }
+
+        let mut out = tempfile::tempfile()?;
+        out.write_all(&nak)?;
+        let mut pack = PackWriter::new(out)?;
+        let mut repository = Repository::open(get)?;
+        let mut seen = HashSet::new();
+        let mut queue = request.wants;
+        while let Some(id) = queue.pop() {
+            if self.haves.contains(&id) || !seen.insert(id.clone()) {
+                continue;
+            }
+            if !is_object_id(&id) {
+                return Err(err(format!("invalid object id `{id}`")));
+            }
+            let (kind, content) = repository.object(&id)?;
+            queue.extend(references(kind, &content));
+            pack.write(kind, &content)?;
+        }
+
+        let mut out = pack.finish()?;
+        out.seek(SeekFrom::Start(0))?;
+        Ok(Box::new(out))
+    }
+}
+
+/// The objects of a repository, loose or in the packs of `objects/info/packs`.
+struct Repository<G> {
+    get: G,
+    /// The names of the packs, and where each object in them is.
+    packs: Vec<String>,
+    entries: HashMap<String, (usize, u64)>,
+    /// The packs fetched so far.
+    pack_files: HashMap<usize, BufReader<File>>,
+    /// The objects delta-compressed entries were based on, by pack and offset.
+    bases: HashMap<(usize, u64), (u8, Vec<u8>)>,
+}
+
+impl<G: FnMut(&str) -> io::Result<Option<File>>> Repository<G> {
+    fn open(mut get: G) -> io::Result<Self> {
+        let mut list = String::new();
+        if let Some(mut file) = get("objects/info/packs")? {
+            file.read_to_string(&mut list)?;
+        }
+        let packs: Vec<String> = list
+            .lines()
+            .filter_map(|line| line.strip_prefix("P "))
+            .filter_map(|name| name.trim().strip_suffix(".pack"))
+            .map(str::to_string)
+            .collect();
+
+        let mut entries = HashMap::new();
+        for (pack, name) in packs.iter().enumerate() {
+            let mut idx = Vec::new();
+            get(&format!("objects/pack/{name}.idx"))?
+                .ok_or_else(|| err(format!("index of pack {name} not found")))?
+                .read_to_end(&mut idx)?;
+            let index =
+                index_entries(&idx).ok_or_else(|| err(format!("invalid index {name}.idx")))?;
+            for (id, offset) in index {
+                entries.entry(id).or_insert((pack, offset));
+            }
+        }
+        Ok(Self {
+            get,
+            packs,
+            entries,
+            pack_files: HashMap::new(),
+            bases: HashMap::new(),
+        })
+    }
+
+    /// The type and the content of the object `id`.
+    fn object(&mut self, id: &str) -> io::Result<(u8, Vec<u8>)> {
+        if let Some(&(pack, offset)) = self.entries.get(id) {
+            return self.packed(pack, offset, 0);
+        }
+
+        let path = format!("objects/{}/{}", &id[..2], &id[2..]);
+        let compressed = (self.get)(&path)?.ok_or_else(|| err(format!("object {id} not found")))?;
+        let mut object = Vec::new();
+        ZlibDecoder::new(compressed)
+            .read_to_end(&mut object)
+            .map_err(|e| err(format!("invalid loose object {id}: {e}")))?;
+        let (kind, content) =
+            parse_loose(&object).ok_or_else(|| err(format!("invalid loose object {id}")))?;
+        let kind = match kind {
+            "commit" => OBJ_COMMIT,
+            "tree" => OBJ_TREE,
+            "blob" => OBJ_BLOB,
+            "tag" => OBJ_TAG,
+            kind => return Err(err(format!("unknown type {kind} of object {id}"))),
+        };
+        Ok((kind, content.to_vec()))
+    }
+
+    /// The type and the content of the object at `offset` in the pack `pack`,
+    /// resolving the `depth` deltas it is the base of.
+    fn packed(&mut self, pack: usize, offset: u64, depth: u32) -> io::Result<(u8, Vec<u8>)> {
+        if let Some(base) = self.bases.get(&(pack, offset)) {
+            return Ok(base.clone());
+        }
+        // git doesn't make chains longer than 50 deltas by default, nor 4095 at all
+        if depth > 4095 {
+            return Err(err(format!(
+                "delta chain too long in pack {}",
+                self.packs[pack]
+            )));
+        }
+
+        let entry = self.entry(pack, offset)?;
+        let (kind, content) = match entry.base {
+            None => (entry.kind, entry.data),
+            Some(base) => {
+                let (kind, base) = match base {
+                    Base::Offset(base) => self.packed(pack, base, depth + 1)?,
+                    Base::Id(id) => match self.entries.get(&id) {
+                        Some(&(pack, offset)) => self.packed(pack, offset, depth + 1)?,
+                        None => self.object(&id)?,
+                    },
+                };
+                let content = apply_delta(&base, &entry.data).ok_or_else(|| {
+                    err(format!(
+                        "invalid delta in pack {} at {offset}",
+                        self.packs[pack]
+                    ))
+                })?;
+                (kind, content)
+            }
+        };
+        if depth > 0 {
+            self.bases.insert((pack, offset), (kind, content.clone()));
+        }
+        Ok((kind, content))
+    }
+
+    /// The entry at `offset` in the pack `pack`, fetching the pack if needed.
+    fn entry(&mut self, pack: usize, offset: u64) -> io::Result<Entry> {
+        let name = &self.packs[pack];
+        if !self.pack_files.contains_key(&pack) {
+            let mut file = (self.get)(&format!("objects/pack/{name}.pack"))?
+                .ok_or_else(|| err(format!("pack {name} not found")))?;
+            let mut signature = [0; 4];
+            if file.metadata()?.len() < 32
+                || file.read_exact(&mut signature).is_err()
+                || &signature != b"PACK"
+            {
+                return Err(err(format!("invalid pack {name}")));
+            }
+            self.pack_files.insert(pack, BufReader::new(file));
+        }
+        let file = self.pack_files.get_mut(&pack).unwrap();
+        parse_entry(file, offset)
+            .ok_or_else(|| err(format!("invalid entry in pack {name} at {offset}")))
+    }
+}
+
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+const OBJ_TAG: u8 = 4;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+/// An entry of a pack: an object, or a delta to apply to its base.
+struct Entry {
+    kind: u8,
+    base: Option<Base>,
+    /// The inflated content of the object, or the delta.
+    data: Vec<u8>,
+}
+
+enum Base {
+    /// The offset of the base in the same pack.
+    Offset(u64),
+    Id(String),
+}
+
+/// The entry at `offset` in the pack `pack`.
+fn parse_entry(pack: &mut BufReader<File>, offset: u64) -> Option<Entry> {
+    pack.seek(SeekFrom::Start(offset)).ok()?;
+    let mut next = || {
+        let mut byte = [0];
+        pack.read_exact(&mut byte).ok()?;
+        Some(byte[0])
+    };
+
+    // The type and size, the size continuing over as many bytes as needed
+    let mut byte = next()?;
+    let kind = (byte >> 4) & 0x07;
+    let mut size = u64::from(byte & 0x0f);
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        byte = next()?;
+        size |= u64::from(byte & 0x7f).checked_shl(shift)?;
+        shift += 7;
+    }
+
+    let base = match kind {
+        OBJ_COMMIT | OBJ_TREE | OBJ_BLOB | OBJ_TAG => None,
+        OBJ_OFS_DELTA => {
+            // Each continuation byte adds one, so that encodings are unique
+            let mut byte = next()?;
+            let mut distance = u64::from(byte & 0x7f);
+            while byte & 0x80 != 0 {
+                byte = next()?;
+                distance = distance
+                    .checked_add(1)?
+                    .checked_mul(128)?
+                    .checked_add(u64::from(byte & 0x7f))?;
+            }
+            Some(Base::Offset(offset.checked_sub(distance)?))
+        }
+        OBJ_REF_DELTA => {
+            let mut id = [0; 20];
+            pack.read_exact(&mut id).ok()?;
+            Some(Base::Id(hex(&id)))
+        }
+        _ => return None,
+    };
+
+    // Inflate one byte more than announced, to tell entries larger than their size
+    let mut data = Vec::with_capacity(usize::try_from(size).ok()?);
+    bufread::ZlibDecoder::new(pack)
+        .take(size.checked_add(1)?)
+        .read_to_end(&mut data)
+        .ok()?;
+    (data.len() as u64 == size).then_some(Entry { kind, base, data })
+}
+
+/// The object made by applying `delta` to `base`.
+fn apply_delta(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut rest = delta;
+    let mut varint = || {
+        let mut value = 0usize;
+        let mut shift = 0;
+        loop {
+            let (&byte, tail) = rest.split_first()?;
+            rest = tail;
+            value |= usize::from(byte & 0x7f).checked_shl(shift)?;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    };
+    if varint()? != base.len() {
+        return None;
+    }
+    let size = varint()?;
+
+    let mut out = Vec::with_capacity(size);
+    while let Some((&op, tail)) = rest.split_first() {
+        rest = tail;
+        if op & 0x80 != 0 {
+            // Copy from the base, the bits of `op` telling which bytes of the offset
+            // and the size follow
+            let mut arguments = [0u8; 7];
+            for (bit, argument) in arguments.iter_mut().enumerate() {
+                if op & (1 << bit) != 0 {
+                    let (&byte, tail) = rest.split_first()?;
+                    rest = tail;
+                    *argument = byte;
+                }
+            }
+            let start = u32::from_le_bytes(arguments[..4].try_into().ok()?) as usize;
+            let len = match u32::from_le_bytes([arguments[4], arguments[5], arguments[6], 0]) {
+                0 => 0x10000,
+                len => len as usize,
+            };
+            out.extend_from_slice(base.get(start..start.checked_add(len)?)?);
+        } else if op != 0 {
+            // Insert the next `op` bytes of the delta
+            let (data, tail) = rest.split_at_checked(usize::from(op))?;
+            rest = tail;
+            out.extend_from_slice(data);
+        } else {
+            return None;
+        }
+    }
+    (out.len() == size).then_some(out)
+}
+
+/// The object ids listed by the version 2 pack index `idx`, with the offsets of
+/// the objects in the pack.
+fn index_entries(idx: &[u8]) -> Option<Vec<(String, u64)>> {
+    if idx.get(..8)? != b"\xfftOc\0\0\0\x02" {
+        return None;
+    }
+    let count = u32::from_be_bytes(idx.get(8 + 255 * 4..8 + 256 * 4)?.try_into().ok()?) as usize;
+    let ids_start = 8 + 256 * 4;
+    let ids = idx.get(ids_start..ids_start + count * 20)?;
+    // The 20-byte ids are followed by one CRC and one offset of 4 bytes for each
+    // object, then by the 8-byte offsets too large for 31 bits
+    let offsets_start = ids_start + count * 24;
+    let offsets = idx.get(offsets_start..offsets_start + count * 4)?;
+    let large_start = offsets_start + count * 4;
+
+    let mut entries = Vec::with_capacity(count);
+    for (id, offset) in ids.chunks(20).zip(offsets.chunks(4)) {
+        let offset = u32::from_be_bytes(offset.try_into().ok()?);
+        let offset = match offset & 0x8000_0000 {
+            0 => u64::from(offset),
+            _ => {
+                let large = large_start + (offset & 0x7fff_ffff) as usize * 8;
+                u64::from_be_bytes(idx.get(large..large + 8)?.try_into().ok()?)
+            }
+        };
+        entries.push((hex(id), offset));
+    }
+    Some(entries)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The type and the content of the inflated loose object `object`.
+fn parse_loose(object: &[u8]) -> Option<(&str, &[u8])> {
+    let nul = object.iter().position(|byte| *byte == 0)?;
+    let header = std::str::from_utf8(&object[..nul]).ok()?;
+    let (kind, _) = header.split_once(' ')?;
+    Some((kind, &object[nul + 1..]))
+}
+
+/// The ids of the objects the object of type `kind` with `content` points to.
+fn references(kind: u8, content: &[u8]) -> Vec<String> {
+    match kind {
+        OBJ_COMMIT | OBJ_TAG => String::from_utf8_lossy(content)
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| {
+                let (key, value) = line.split_once(' ')?;
+                matches!(key, "tree" | "parent" | "object").then(|| value.to_string())
+            })
+            .collect(),
+        OBJ_TREE => {
+            let mut ids = Vec::new();
+            let mut rest = content;
+            while let Some(nul) = rest.iter().position(|byte| *byte == 0) {
+                let Some(id) = rest.get(nul + 1..nul + 21) else {
+                    break;
+                };
+                // Submodules point to commits of other repositories
+                if !rest.starts_with(b"160000 ") {
+                    ids.push(hex(id));
+                }
+                rest = &rest[nul + 21..];
+            }
+            ids
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Writer of a pack to the end of a file, one object at a time.
+///
+/// The objects are written whole: the deltas of the packs they come from may be
+/// based on objects that aren't sent.
+struct PackWriter {
+    out: BufWriter<File>,
+    /// Where the pack starts in the file, and the number of objects written so far.
+    start: u64,
+    count: u32,
+}
+
+impl PackWriter {
+    fn new(mut file: File) -> io::Result<Self> {
+        let start = file.seek(SeekFrom::End(0))?;
+        let mut out = BufWriter::new(file);
+        // The number of objects is filled in once they are all written
+        out.write_all(b"PACK\0\0\0\x02\0\0\0\0")?;
+        Ok(Self {
+            out,
+            start,
+            count: 0,
+        })
+    }
+
+    /// Write the object of pack object type `kind` with `content`.
+    fn write(&mut self, kind: u8, content: &[u8]) -> io::Result<()> {
+        self.count = self
+            .count
+            .checked_add(1)
+            .ok_or_else(|| err("too many objects".to_string()))?;
+
+        // The type and size, the size continuing over as many bytes as needed
+        let mut size = content.len();
+        let mut byte = (kind << 4) | (size & 0x0f) as u8;
+        size >>= 4;
+        while size > 0 {
+            self.out.write_all(&[byte | 0x80])?;
+            byte = (size & 0x7f) as u8;
+            size >>= 7;
+        }
+        self.out.write_all(&[byte])?;
+
+        let mut encoder = ZlibEncoder::new(&mut self.out, Compression::default());
+        encoder.write_all(content)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    /// Fill in the number of objects and append the checksum of the pack.
+    fn finish(self) -> io::Result<File> {
+        let mut file = self.out.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(self.start + 8))?;
+        file.write_all(&self.count.to_be_bytes())?;
+
+        file.seek(SeekFrom::Start(self.start))?;
+        let mut checksum = sha1_smol::Sha1::new();
+        let mut reader = BufReader::new(&mut file);
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            checksum.update(buf);
+            let len = buf.len();
+            reader.consume(len);
+        }
+        file.write_all(&checksum.digest().bytes())?;
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::process::Command;
+
+    use super::*;
+
+    const ID: &str = "0123456789abcdef0123456789abcdef01234567";
+    const URL: &str = "http://example.com/repo/info/refs";
+
+    #[test]
+    fn advertisement_lists_the_refs_and_head() {
+        let refs = format!("{ID}\trefs/heads/main\n{ID}\trefs/tags/v1\n");
+        let advertisement =
+            advertisement("upload-pack", &refs, URL, Some("ref: refs/heads/main\n")).unwrap();
+        let mut expected = Vec::new();
+        pkt_line(&mut expected, "# service=git-upload-pack\n");
+        expected.extend_from_slice(b"0000");
+        pkt_line(
+            &mut expected,
+            &format!("{ID} HEAD\0ofs-delta symref=HEAD:refs/heads/main\n"),
+        );
+        pkt_line(&mut expected, &format!("{ID} refs/heads/main\n"));
+        pkt_line(&mut expected, &format!("{ID} refs/tags/v1\n"));
+        expected.extend_from_slice(b"0000");
+        assert_eq!(advertisement, expected);
+    }
+
+    #[test]
+    fn advertisement_of_files_that_are_not_info_refs_fails() {
+        for refs in [
+            "<html><body>Not found</body></html>\n".to_string(),
+            format!("{ID} refs/heads/main\n"),
+            format!("{ID}\trefs/heads/main\nnot a ref\n"),
+            format!("{}\trefs/heads/main\n", &ID[..39]),
+            format!("{ID}\t\n"),
+        ] {
+            let error = advertisement("upload-pack", &refs, URL, None).unwrap_err();
+            let expected = format!("{URL} not valid: is this a git repository?");
+            assert!(error.to_string().starts_with(&expected), "{refs}");
+        }
+    }
+
+    #[test]
+    fn advertisement_of_sha256_repositories_fails() {
+        let id = "0123456789abcdef".repeat(4);
+        let refs = format!("{id}\trefs/heads/main\n");
+        let error = advertisement("upload-pack", &refs, URL, None).unwrap_err();
+        let expected = format!("{URL} is a SHA-256 repository");
+        assert!(error.to_string().starts_with(&expected), "{error}");
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(["-c", "user.name=a", "-c", "user.email=a@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {args:?}: {output:?}");
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /// Commit a new version of `file.txt`, made of lines numbered from `first`.
+    fn commit(dir: &Path, first: usize) {
+        let lines: String = (first..first + 200)
+            .map(|n| format!("line {n}\n"))
+            .collect();
+        std::fs::write(dir.join("file.txt"), lines).unwrap();
+        let other = format!("{first}\n");
+        std::fs::write(dir.join(format!("file-{first}.txt")), other).unwrap();
+        git(dir, &["add", "."]);
+        git(dir, &["commit", "-q", "-m", &format!("commit {first}")]);
+    }
+
+    /// A repository of six commits: the first three in a pack with deltas of
+    /// `file.txt` against the blobs before it, the next two in a second pack pointing
+    /// to objects of the first one, and the last one loose.
+    fn repository() -> tempfile::TempDir {
+        let source = tempfile::tempdir().unwrap();
+        git(source.path(), &["init", "-q"]);
+        for first in 0..3 {
+            commit(source.path(), first);
+        }
+        git(source.path(), &["repack", "-a", "-d", "-q"]);
+        for first in 3..5 {
+            commit(source.path(), first);
+        }
+        git(source.path(), &["repack", "-d", "-q"]);
+        commit(source.path(), 5);
+        git(source.path(), &["update-server-info"]);
+        source
+    }
+
+    /// The request for the objects of `want`, with the objects of `haves`.
+    fn request(want: &str, haves: &[&str], done: bool) -> Vec<u8> {
+        let mut request = Vec::new();
+        pkt_line(&mut request, &format!("want {want} ofs-delta\n"));
+        request.extend_from_slice(b"0000");
+        for have in haves {
+            pkt_line(&mut request, &format!("have {have}\n"));
+        }
+        if done {
+            pkt_line(&mut request, "done\n");
+        } else {
+            request.extend_from_slice(b"0000");
+        }
+        request
+    }
+
+    /// Answer `request` from the files of the repository at `dir`.
+    fn upload_pack(fetch: &mut Fetch, dir: &Path, request: &[u8]) -> Vec<u8> {
+        let git_dir = dir.join(".git");
+        let mut response = Vec::new();
+        fetch
+            .upload_pack(request, |path| match File::open(git_dir.join(path)) {
+                Ok(file) => Ok(Some(file)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            })
+            .unwrap()
+            .read_to_end(&mut response)
+            .unwrap();
+        response
+    }
+
+    /// The ids of the objects of `pack`, checked by `git index-pack`, along with the
+    /// objects they point to if `complete`.
+    fn pack_objects(pack: &[u8], complete: bool) -> HashSet<String> {
+        let target = tempfile::tempdir().unwrap();
+        git(target.path(), &["init", "-q", "--bare"]);
+        std::fs::write(target.path().join("fetched.pack"), pack).unwrap();
+        match complete {
+            true => git(target.path(), &["index-pack", "--strict", "fetched.pack"]),
+            false => git(target.path(), &["index-pack", "fetched.pack"]),
+        };
+        git(target.path(), &["verify-pack", "-v", "fetched.idx"])
+            .lines()
+            .filter_map(|line| line.get(..40))
+            .filter(|id| is_object_id(id))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn upload_pack_sends_objects_of_two_packs_and_loose_ones() {
+        let source = repository();
+        let git_dir = source.path().join(".git");
+        let packs = std::fs::read_to_string(git_dir.join("objects/info/packs")).unwrap();
+        let packs: Vec<&str> = packs
+            .lines()
+            .filter_map(|line| line.strip_prefix("P "))
+            .collect();
+        assert_eq!(packs.len(), 2);
+        let deltified = packs.iter().any(|pack| {
+            let idx = format!(".git/objects/pack/{}", pack.replace(".pack", ".idx"));
+            git(source.path(), &["verify-pack", "-v", &idx]).contains("chain length")
+        });
+        assert!(deltified);
+        let head = git(source.path(), &["rev-parse", "HEAD"]);
+
+        let response = upload_pack(
+            &mut Fetch::default(),
+            source.path(),
+            &request(head.trim(), &[], true),
+        );
+        let pack = response.strip_prefix(b"0008NAK\n".as_slice()).unwrap();
+        let objects = git(source.path(), &["rev-list", "--objects", "HEAD"]);
+        let ids: HashSet<String> = objects
+            .lines()
+            .filter_map(|line| line.get(..40))
+            .map(str::to_string)
+            .collect();
+        assert_eq!(ids.len(), 6 * 4);
+        assert_eq!(pack_objects(pack, true), ids);
+    }
+
+    #[test]
+    fn upload_pack_stops_at_the_objects_the_client_has() {
+        let source = repository();
+        let head = git(source.path(), &["rev-parse", "HEAD"]);
+        let parent = git(source.path(), &["rev-parse", "HEAD~"]);
+        let (head, parent) = (head.trim(), parent.trim());
+
+        // The objects the client has are kept from the requests before the last one
+        let mut fetch = Fetch::default();
+        let response = upload_pack(&mut fetch, source.path(), &request(head, &[parent], false));
+        assert_eq!(response, b"0008NAK\n");
+        let response = upload_pack(&mut fetch, source.path(), &request(head, &[], true));
+        let pack = response.strip_prefix(b"0008NAK\n".as_slice()).unwrap();
+
+        let objects = pack_objects(pack, false);
+        assert!(objects.contains(head));
+        assert!(!objects.contains(parent));
+        let tree = git(source.path(), &["rev-parse", "HEAD~:"]);
+        assert!(!objects.contains(tree.trim()));
+    }
+}
@@ -0,0 +1,199 @@
+//! Lookup of `http.*` settings in the user's git config.
+
+use std::env;
+use std::path::PathBuf;
+
+use git2::Config;
+use url::Url;
+
+fn open() -> Option<Config> {
+    Config::open_default().ok()
+}
+
+/// The integer value of `key`, if set.
+pub(crate) fn int(key: &str) -> Option<i32> {
+    open()?.get_i32(key).ok()
+}
+
+/// How specifically the URL of a `http.<url>.*` entry matches a URL, ordered like git does:
+/// by length of the matched host, then of the matched path, then whether a user matched.
+type Specificity = (usize, usize, bool);
+
+/// The `http.<name>` and `http.<url>.<name>` entries of the user's git config that
+/// apply to a URL, read once for every setting of the transport.
+#[derive(Debug, Default)]
+pub(crate) struct Settings {
+    /// The lowercase name, the specificity and the value of the matching entries, in
+    /// the order they appear in the config files.
+    entries: Vec<(String, Specificity, String)>,
+}
+
+impl Settings {
+    /// Read the entries applying to `url` from the user's git config.
+    pub(crate) fn for_url(url: &Url) -> Settings {
+        let mut entries = Vec::new();
+        if let Some(config) = open() {
+            if let Ok(mut iter) = config.entries(Some(r"^http\.")) {
+                while let Some(Ok(entry)) = iter.next() {
+                    if let (Some(name), Some(value)) = (entry.name(), entry.value()) {
+                        entries.push((name.to_string(), value.to_string()));
+                    }
+                }
+            }
+        }
+        Settings::from_entries(entries, url)
+    }
+
+    /// Keep the entries of `entries`, as `(name, value)` pairs, applying to `url`.
+    fn from_entries(entries: Vec<(String, String)>, url: &Url) -> Settings {
+        let entries = entries
+            .into_iter()
+            .filter_map(|(entry_name, value)| {
+                let (name, specificity) = entry_matches(&entry_name, url)?;
+                Some((name.to_ascii_lowercase(), specificity, value))
+            })
+            .collect();
+        Settings { entries }
+    }
+
+    /// The value of the `http.<url>.<name>` entry matching most specifically,
+    /// falling back to `http.<name>`.
+    pub(crate) fn string(&self, name: &str) -> Option<&str> {
+        let mut best: Option<(Specificity, &str)> = None;
+        for (specificity, value) in self.named(name) {
+            // Later entries win over earlier ones that are as specific
+            if best.is_none_or(|(best, _)| specificity >= best) {
+                best = Some((specificity, value));
+            }
+        }
+        best.map(|(_, value)| value)
+    }
+
+    /// The boolean value of the `http.<name>` entry, see [`string`](Self::string).
+    pub(crate) fn bool(&self, name: &str) -> Option<bool> {
+        Config::parse_bool(self.string(name)?).ok()
+    }
+
+    /// The 64-bit integer value of the `http.<name>` entry, see [`string`](Self::string).
+    pub(crate) fn int64(&self, name: &str) -> Option<i64> {
+        Config::parse_i64(self.string(name)?).ok()
+    }
+
+    /// The path value of the `http.<name>` entry, with `~/` expanded, see
+    /// [`string`](Self::string).
+    pub(crate) fn path(&self, name: &str) -> Option<PathBuf> {
+        let path = self.string(name)?;
+        match path.strip_prefix("~/") {
+            Some(rest) => Some(PathBuf::from(env::var_os("HOME")?).join(rest)),
+            None => Some(PathBuf::from(path)),
+        }
+    }
+
+    /// The values of every `http.<name>` and `http.<url>.<name>` entry, in the order
+    /// they appear in the config files.
+    pub(crate) fn all(&self, name: &str) -> Vec<&str> {
+        self.named(name).map(|(_, value)| value).collect()
+    }
+
+    /// The entries named `name`, in the order they appear in the config files.
+    fn named<'a>(&'a self, name: &str) -> impl Iterator<Item = (Specificity, &'a str)> {
+        let name = name.to_ascii_lowercase();
+        self.entries
+            .iter()
+            .filter(move |(entry, _, _)| *entry == name)
+            .map(|(_, specificity, value)| (*specificity, value.as_str()))
+    }
+}
+
+/// The name of the entry `entry_name` and how specifically it matches `url`, if it
+/// is a `http.<name>` or `http.<url>.<name>` entry applying to `url`.
+fn entry_matches<'a>(entry_name: &'a str, url: &Url) -> Option<(&'a str, Specificity)> {
+    let rest = entry_name.strip_prefix("http.")?;
+    match rest.rsplit_once('.') {
+        Some((pattern, name)) => Some((name, matches(pattern, url)?)),
+        None => Some((rest, (0, 0, false))),
+    }
+}
+
+/// How specifically `pattern`, the URL of a `http.<url>.*` or `credential.<url>.*`
+/// entry, matches `url`.
+pub(crate) fn matches(pattern: &str, url: &Url) -> Option<Specificity> {
+    let pattern = Url::parse(pattern).ok()?;
+    if !pattern.scheme().eq_ignore_ascii_case(url.scheme()) {
+        return None;
+    }
+
+    // Host names match label by label, where `*` matches any single label
+    let pattern_host = pattern.host_str()?;
+    let host = url.host_str()?;
+    let pattern_labels: Vec<&str> = pattern_host.split('.').collect();
+    let labels: Vec<&str> = host.split('.').collect();
+    if pattern_labels.len() != labels.len()
+        || !pattern_labels
+            .iter()
+            .zip(&labels)
+            .all(|(pattern, label)| *pattern == "*" || pattern.eq_ignore_ascii_case(label))
+    {
+        return None;
+    }
+    if pattern.port_or_known_default() != url.port_or_known_default() {
+        return None;
+    }
+
+    let user_matched = !pattern.username().is_empty();
+    if user_matched && pattern.username() != url.username() {
+        return None;
+    }
+
+    // Paths match whole segments
+    let pattern_path = pattern.path().trim_end_matches('/');
+    let path = url.path();
+    let path_matched = path == pattern_path
+        || (path.starts_with(pattern_path) && path[pattern_path.len()..].starts_with('/'));
+    if !path_matched {
+        return None;
+    }
+
+    Some((pattern_host.len(), pattern_path.len(), user_matched))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(entries: &[(&str, &str)], url: &str) -> Settings {
+        let entries = entries
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Settings::from_entries(entries, &Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn most_specific_entry_wins() {
+        let settings = settings(
+            &[
+                ("http.https://example.com/team.postbuffer", "3"),
+                ("http.postbuffer", "1"),
+                ("http.https://*.com.postbuffer", "2"),
+                ("http.https://other.com.postbuffer", "4"),
+            ],
+            "https://example.com/team/repo.git",
+        );
+        assert_eq!(settings.int64("postBuffer"), Some(3));
+        assert_eq!(settings.all("postBuffer"), ["3", "1", "2"]);
+        assert_eq!(settings.string("proxy"), None);
+    }
+
+    #[test]
+    fn later_entry_wins_over_one_as_specific() {
+        let settings = settings(
+            &[
+                ("http.https://example.com.sslverify", "false"),
+                ("http.https://example.com.sslverify", "true"),
+            ],
+            "https://example.com/repo.git",
+        );
+        assert_eq!(settings.bool("sslVerify"), Some(true));
+    }
+}
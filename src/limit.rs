@@ -0,0 +1,49 @@
+//! The limit of requests sent to a host at the same time, like git's
+//! `http.maxRequests`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// The number of requests in flight, by host, shared by every transport
+/// registered with the same builder.
+#[derive(Clone, Default)]
+pub(crate) struct RequestLimiter {
+    in_flight: Arc<(Mutex<HashMap<String, usize>>, Condvar)>,
+}
+
+impl RequestLimiter {
+    /// Wait until fewer than `max` requests are in flight to `host`, and count one
+    /// more until the returned guard is dropped.
+    pub(crate) fn acquire(&self, host: &str, max: usize) -> Permit {
+        let (in_flight, released) = &*self.in_flight;
+        let mut counts = in_flight.lock().unwrap();
+        while counts.get(host).copied().unwrap_or(0) >= max {
+            counts = released.wait(counts).unwrap();
+        }
+        *counts.entry(host.to_string()).or_insert(0) += 1;
+        Permit {
+            limiter: self.clone(),
+            host: host.to_string(),
+        }
+    }
+}
+
+/// A request in flight to `host`.
+pub(crate) struct Permit {
+    limiter: RequestLimiter,
+    host: String,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let (in_flight, released) = &*self.limiter.in_flight;
+        let mut counts = in_flight.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.host) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.host);
+            }
+        }
+        released.notify_all();
+    }
+}
@@ -0,0 +1,172 @@
+//! Connections leaving from a given local address.
+//!
+//! ureq 2 opens its connections itself, to the addresses its resolver returns, with
+//! no way to bind their sockets first. So the resolver connects to the server from
+//! the local address itself, and returns the address of a loopback listener relaying
+//! the one connection ureq then opens to it. TLS, proxies and the pool of ureq see the
+//! relayed connection as any other.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+
+/// The time to wait for connections when no connect timeout is configured, that of ureq.
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A failure to bind to the local address or to connect from it.
+///
+/// It happens in the resolver, so ureq reports it as a failure to resolve the host.
+#[derive(Debug)]
+pub(crate) struct Failure(String);
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl error::Error for Failure {}
+
+/// The failure to bind to the local address or to connect from it that caused
+/// `error`, if any.
+pub(crate) fn failure(error: &ureq::Error) -> Option<&Failure> {
+    let mut source = error::Error::source(error);
+    while let Some(error) = source {
+        let failure = error
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::get_ref)
+            .and_then(|error| error.downcast_ref::<Failure>());
+        if failure.is_some() {
+            return failure;
+        }
+        source = error.source();
+    }
+    None
+}
+
+/// The resolver of agents whose connections leave from `local`, to the addresses
+/// `lookup` resolves.
+///
+/// `nodelay` and `keepalive` are set on the connections to the server, ureq setting
+/// them on the relayed ones only.
+pub(crate) struct Resolver<R> {
+    local: IpAddr,
+    lookup: R,
+    connect_timeout: Duration,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+}
+
+impl<R> Resolver<R> {
+    pub(crate) fn new(
+        local: IpAddr,
+        lookup: R,
+        connect_timeout: Option<Duration>,
+        nodelay: bool,
+        keepalive: Option<Duration>,
+    ) -> Self {
+        Self {
+            local,
+            lookup,
+            connect_timeout: connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            nodelay,
+            keepalive,
+        }
+    }
+
+    /// Connect to `addr` from the local address.
+    fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket
+            .bind(&SocketAddr::new(self.local, 0).into())
+            .map_err(|e| fail(e.kind(), format!("could not bind to {}: {}", self.local, e)))?;
+        socket.connect_timeout(&addr.into(), self.connect_timeout)?;
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(time) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        Ok(socket.into())
+    }
+}
+
+impl<R: ureq::Resolver> ureq::Resolver for Resolver<R> {
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        let mut error = None;
+        // Only addresses of the family of the local one can be reached from it
+        for addr in self
+            .lookup
+            .resolve(netloc)?
+            .into_iter()
+            .filter(|addr| addr.is_ipv4() == self.local.is_ipv4())
+        {
+            debug!("connecting to {} at {} from {}", netloc, addr, self.local);
+            match self.connect(addr) {
+                Ok(server) => return relay(server, self.connect_timeout).map(|addr| vec![addr]),
+                Err(e) if e.get_ref().is_some_and(|e| e.is::<Failure>()) => return Err(e),
+                Err(e) => error = Some(e),
+            }
+        }
+
+        Err(match error {
+            Some(e) => fail(
+                e.kind(),
+                format!("could not connect to {} from {}: {}", netloc, self.local, e),
+            ),
+            None => fail(
+                io::ErrorKind::NotFound,
+                format!("{} has no address reachable from {}", netloc, self.local),
+            ),
+        })
+    }
+}
+
+fn fail(kind: io::ErrorKind, message: String) -> io::Error {
+    io::Error::new(kind, Failure(message))
+}
+
+/// Relay the first connection to a new loopback listener to `server`, returning the
+/// address of the listener.
+///
+/// The listener is dropped when nothing connects to it within `timeout`.
+fn relay(server: TcpStream, timeout: Duration) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        let deadline = Instant::now() + timeout;
+        let client = loop {
+            match listener.accept() {
+                Ok((client, _)) => break client,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err(_) => return,
+            }
+        };
+        let (Ok(()), Ok(to_client), Ok(to_server)) = (
+            client.set_nonblocking(false),
+            client.try_clone(),
+            server.try_clone(),
+        ) else {
+            return;
+        };
+        thread::spawn(move || copy(client, to_server));
+        copy(server, to_client);
+    });
+    Ok(addr)
+}
+
+/// Copy everything `from` sends to `to`, then close both as the connection is over.
+fn copy(mut from: TcpStream, mut to: TcpStream) {
+    let _ = io::copy(&mut from, &mut to);
+    let _ = to.flush();
+    let _ = to.shutdown(Shutdown::Both);
+    let _ = from.shutdown(Shutdown::Both);
+}
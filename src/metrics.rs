@@ -0,0 +1,43 @@
+//! Transfer metrics reported to the callback given to
+//! [`UreqTransportBuilder::metrics`](crate::UreqTransportBuilder::metrics).
+
+use std::time::Duration;
+
+use crate::TlsVersion;
+
+/// What sending a request of libgit2 and reading its response took.
+///
+/// It is reported once the response headers are received, and again once the
+/// response body has been read entirely or libgit2 is done with it.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct Metrics {
+    /// The git service the request is part of, `upload-pack` or `receive-pack`.
+    pub service: &'static str,
+    /// The method of the request, `GET` for reference advertisements and `POST` otherwise.
+    pub method: &'static str,
+    /// The number of HTTP requests sent, including retries, redirects and
+    /// authentication challenges.
+    pub requests: u32,
+    /// The number of requests retried after a transient failure.
+    pub retries: u32,
+    /// The number of redirects followed.
+    pub redirects: u32,
+    /// The number of bytes of request bodies sent, over every request.
+    pub bytes_sent: u64,
+    /// The number of bytes of the response body read so far.
+    pub bytes_received: u64,
+    /// The time it took to send the requests and receive the headers of the response.
+    pub response_time: Duration,
+    /// The time it took to read the response, since the first request was sent.
+    /// `None` until libgit2 is done with it.
+    pub transfer_time: Option<Duration>,
+    /// The TLS version negotiated with the server the response came from.
+    ///
+    /// `None` over plain HTTP, with the native-tls backend which doesn't tell, or
+    /// with an agent given to [`UreqTransportBuilder::agent`](crate::UreqTransportBuilder::agent).
+    pub tls_version: Option<TlsVersion>,
+    /// The name of the cipher suite negotiated with the server, such as
+    /// `TLS13_AES_128_GCM_SHA256`, when `tls_version` is known.
+    pub cipher_suite: Option<String>,
+}
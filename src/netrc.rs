@@ -0,0 +1,105 @@
+//! Lookup of HTTP credentials in the user's netrc file, like curl does.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// A `machine` (or `default`) entry of a netrc file.
+#[derive(Debug, Default)]
+struct Entry {
+    /// `None` for the `default` entry.
+    machine: Option<String>,
+    login: Option<String>,
+    password: Option<String>,
+}
+
+/// Find the login and password for `host` in the netrc file.
+///
+/// The file is read from `$NETRC` if set, `~/.netrc` otherwise (`%HOME%\_netrc`
+/// on Windows). Returns `None` when there is no file or no matching entry.
+pub(crate) fn lookup(host: &str) -> Option<(String, String)> {
+    let contents = fs::read_to_string(path()?).ok()?;
+    find(&parse(&contents), host)
+}
+
+fn path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("NETRC") {
+        return Some(PathBuf::from(path));
+    }
+
+    let home = PathBuf::from(env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?);
+    if cfg!(windows) {
+        let path = home.join("_netrc");
+        if path.exists() {
+            return Some(path);
+        }
+    }
+    Some(home.join(".netrc"))
+}
+
+fn parse(contents: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut lines = contents.lines();
+    let mut tokens = Vec::new();
+
+    while let Some(line) = lines.next() {
+        // Comments run until the end of the line
+        let line = match line.find('#') {
+            Some(index) => &line[..index],
+            None => line,
+        };
+        let start = tokens.len();
+        tokens.extend(line.split_whitespace());
+
+        // Macro definitions are meaningless to us, their body runs until the next blank line
+        if tokens[start..].contains(&"macdef") {
+            for line in lines.by_ref() {
+                if line.trim().is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" => entries.push(Entry {
+                machine: tokens.next().map(str::to_string),
+                ..Default::default()
+            }),
+            "default" => entries.push(Entry::default()),
+            "login" => {
+                if let (Some(entry), Some(login)) = (entries.last_mut(), tokens.next()) {
+                    entry.login = Some(login.to_string());
+                }
+            }
+            "password" => {
+                if let (Some(entry), Some(password)) = (entries.last_mut(), tokens.next()) {
+                    entry.password = Some(password.to_string());
+                }
+            }
+            "account" | "macdef" => {
+                tokens.next();
+            }
+            _ => {}
+        }
+    }
+
+    entries
+}
+
+fn find(entries: &[Entry], host: &str) -> Option<(String, String)> {
+    // The `default` entry is deliberately ignored, hosts without an entry don't use netrc at all
+    let entry = entries.iter().find(|entry| {
+        entry
+            .machine
+            .as_deref()
+            .is_some_and(|machine| machine.eq_ignore_ascii_case(host))
+    })?;
+
+    Some((
+        entry.login.clone().unwrap_or_default(),
+        entry.password.clone()?,
+    ))
+}
@@ -0,0 +1,219 @@
+//! Pinning of the certificates of servers, checked once the TLS backend trusts them,
+//! like curl's `--pinnedpubkey` that git's `http.pinnedPubkey` sets.
+#![cfg_attr(not(any(feature = "rustls", feature = "native-tls")), allow(dead_code))]
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use git2::{Error, ErrorClass, ErrorCode};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::SubjectPublicKeyInfoDer;
+use sha2::{Digest, Sha256};
+
+/// A SHA-256 hash the certificate of servers must match, for
+/// [`UreqTransportBuilder::pin_certificate`](crate::UreqTransportBuilder::pin_certificate).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CertificatePin {
+    /// The hash of the DER encoding of the server's own certificate, which changes
+    /// whenever the certificate is renewed.
+    Certificate([u8; 32]),
+    /// The hash of the DER encoding of the subject public key info of the server's
+    /// certificate, which stays the same across renewals with the same key.
+    PublicKey([u8; 32]),
+}
+
+/// The certificate of the server matched none of the pins.
+#[derive(Debug)]
+pub(crate) struct PinMismatch(pub(crate) String);
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl error::Error for PinMismatch {}
+
+/// Check that the DER certificate `certificate` matches one of `pins`.
+pub(crate) fn check(pins: &[CertificatePin], certificate: &[u8]) -> Result<(), PinMismatch> {
+    let public_key = subject_public_key_info(certificate).map(sha256);
+    let certificate = sha256(certificate);
+    let matches = pins.iter().any(|pin| match pin {
+        CertificatePin::Certificate(hash) => *hash == certificate,
+        CertificatePin::PublicKey(hash) => Some(*hash) == public_key,
+    });
+    if matches {
+        return Ok(());
+    }
+
+    Err(PinMismatch(match public_key {
+        Some(hash) => format!(
+            "certificate pin mismatch, the public key of the server is sha256//{}",
+            STANDARD.encode(hash)
+        ),
+        None => format!(
+            "certificate pin mismatch, the certificate of the server is sha256//{}",
+            STANDARD.encode(certificate)
+        ),
+    }))
+}
+
+/// Parse the value of `http.pinnedPubkey`: `sha256//` followed by the base64 of a
+/// hash, several separated by `;`, or the path of a PEM or DER public key.
+pub(crate) fn parse_pinned_pubkey(value: &str) -> Result<Vec<CertificatePin>, Error> {
+    let invalid = |reason: String| {
+        Error::new(
+            ErrorCode::Invalid,
+            ErrorClass::Ssl,
+            format!("invalid http.pinnedPubkey `{value}`: {reason}"),
+        )
+    };
+
+    let value = value.trim();
+    if !value.starts_with("sha256//") {
+        let path = Path::new(value);
+        let key = fs::read(path).map_err(|e| invalid(format!("failed to read it: {e}")))?;
+        let key = match key.windows(11).any(|window| window == b"-----BEGIN ") {
+            true => SubjectPublicKeyInfoDer::from_pem_slice(&key)
+                .map_err(|e| invalid(format!("failed to parse it: {e}")))?
+                .to_vec(),
+            false => key,
+        };
+        return Ok(vec![CertificatePin::PublicKey(sha256(&key))]);
+    }
+
+    value
+        .split(';')
+        .map(|pin| {
+            let hash = pin
+                .trim()
+                .strip_prefix("sha256//")
+                .ok_or_else(|| invalid(format!("`{pin}` doesn't start with sha256//")))?;
+            let hash = STANDARD
+                .decode(hash)
+                .ok()
+                .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+                .ok_or_else(|| invalid(format!("`{pin}` isn't the base64 of a SHA-256 hash")))?;
+            Ok(CertificatePin::PublicKey(hash))
+        })
+        .collect()
+}
+
+fn sha256(bytes: &[u8]) -> [u8; 32] {
+    Sha256::digest(bytes).into()
+}
+
+/// The DER subject public key info of the DER certificate `certificate`, the seventh
+/// element of its `tbsCertificate`, counting the optional version.
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    let (0x30, _, certificate) = der_element(certificate)? else {
+        return None;
+    };
+    let (0x30, _, mut rest) = der_element(certificate)? else {
+        return None;
+    };
+    // The version, the serial number, the signature algorithm, the issuer, the
+    // validity and the subject
+    for index in 0..6 {
+        let (tag, element, _) = der_element(rest)?;
+        if index == 0 && tag != 0xa0 {
+            continue;
+        }
+        rest = &rest[element.len()..];
+    }
+    match der_element(rest)? {
+        (0x30, element, _) => Some(element),
+        _ => None,
+    }
+}
+
+/// The tag, the whole encoding and the content of the DER element at the start of
+/// `input`.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *input.first()?;
+    let first = *input.get(1)?;
+    let (header, len) = match first {
+        0..=0x7f => (2, first as usize),
+        0x81..=0x84 => {
+            let count = (first & 0x7f) as usize;
+            let len = input
+                .get(2..2 + count)?
+                .iter()
+                .fold(0, |len, byte| (len << 8) | *byte as usize);
+            (2 + count, len)
+        }
+        _ => return None,
+    };
+    let element = input.get(..header.checked_add(len)?)?;
+    Some((tag, element, &element[header..]))
+}
+
+#[cfg(all(feature = "rustls", not(feature = "native-tls")))]
+pub(crate) use verifier::PinnedVerifier;
+
+#[cfg(all(feature = "rustls", not(feature = "native-tls")))]
+mod verifier {
+    use std::sync::Arc;
+
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::{CertificateError, DigitallySignedStruct, OtherError, SignatureScheme};
+    use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+
+    use super::CertificatePin;
+
+    /// Checks the pins once `inner` trusts the certificate.
+    #[derive(Debug)]
+    pub(crate) struct PinnedVerifier {
+        pub(crate) inner: Arc<dyn ServerCertVerifier>,
+        pub(crate) pins: Vec<CertificatePin>,
+    }
+
+    impl ServerCertVerifier for PinnedVerifier {
+        fn verify_server_cert(
+            &self,
+            end_entity: &CertificateDer<'_>,
+            intermediates: &[CertificateDer<'_>],
+            server_name: &ServerName<'_>,
+            ocsp_response: &[u8],
+            now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            let verified = self.inner.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+            super::check(&self.pins, end_entity).map_err(|e| {
+                rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(Arc::new(e))))
+            })?;
+            Ok(verified)
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            self.inner.verify_tls12_signature(message, cert, dss)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            self.inner.verify_tls13_signature(message, cert, dss)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.inner.supported_verify_schemes()
+        }
+    }
+}
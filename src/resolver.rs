@@ -0,0 +1,215 @@
+//! Resolution of the hosts the agent connects to: the addresses given to the
+//! builder, then the system resolver, racing the address families of hosts that
+//! have both like happy eyeballs (RFC 8305).
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// The delay between two connection attempts, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How often the connection attempts in progress are checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// The time connection attempts are given when no connect timeout is configured.
+const DEFAULT_RACE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) struct Resolver {
+    /// The addresses given to `UreqTransportBuilder::resolve`, by lowercase host.
+    pub(crate) hosts: HashMap<String, SocketAddr>,
+    pub(crate) happy_eyeballs: bool,
+    pub(crate) connect_timeout: Option<Duration>,
+}
+
+impl ureq::Resolver for Resolver {
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        let host = match netloc.rsplit_once(':') {
+            Some((host, _)) => host,
+            None => netloc,
+        };
+        if let Some(addr) = self.hosts.get(&host.to_ascii_lowercase()) {
+            return Ok(vec![*addr]);
+        }
+
+        let addrs: Vec<SocketAddr> = netloc.to_socket_addrs()?.collect();
+        match self.happy_eyeballs {
+            true => Ok(self.race(netloc, addrs)),
+            false => Ok(addrs),
+        }
+    }
+}
+
+impl Resolver {
+    /// Order `addrs` so the one that accepted a connection first comes first, when
+    /// they are both IPv6 and IPv4 addresses.
+    ///
+    /// ureq connects to the addresses one after the other, waiting for each to time
+    /// out, so the race is run here. The attempts are started one after the other,
+    /// every `CONNECTION_ATTEMPT_DELAY` or as soon as the previous ones failed, on
+    /// non-blocking sockets of this thread. They are closed once one connects:
+    /// ureq 2 can't be handed a connected socket, it connects to the winner again.
+    fn race(&self, netloc: &str, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        if !addrs.iter().any(SocketAddr::is_ipv6) || !addrs.iter().any(SocketAddr::is_ipv4) {
+            return addrs;
+        }
+
+        let timeout = self.connect_timeout.unwrap_or(DEFAULT_RACE_TIMEOUT);
+        let mut waiting = interleave(&addrs).into_iter();
+        let mut attempts: Vec<(SocketAddr, Socket, Instant)> = Vec::new();
+        let mut next_attempt = Instant::now();
+        let winner = loop {
+            let now = Instant::now();
+            if now >= next_attempt || attempts.is_empty() {
+                if let Some(addr) = waiting.next() {
+                    if let Some(socket) = start_connecting(addr) {
+                        attempts.push((addr, socket, now));
+                    }
+                    next_attempt = now + CONNECTION_ATTEMPT_DELAY;
+                    continue;
+                }
+            }
+
+            let mut connected = None;
+            attempts.retain(|(addr, socket, started)| match state(socket) {
+                Some(true) => {
+                    connected = connected.or(Some(*addr));
+                    true
+                }
+                Some(false) => false,
+                None => started.elapsed() < timeout,
+            });
+            if connected.is_some() {
+                break connected;
+            }
+            // When every attempt fails, ureq tries them again and reports why
+            if attempts.is_empty() && waiting.len() == 0 {
+                break None;
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        // Closing the sockets abandons the attempts still in progress
+        drop(attempts);
+
+        match winner {
+            Some(winner) => {
+                debug!("{} connected first to {}", netloc, winner);
+                let mut addrs = addrs;
+                addrs.retain(|addr| *addr != winner);
+                addrs.insert(0, winner);
+                addrs
+            }
+            None => addrs,
+        }
+    }
+}
+
+/// A non-blocking socket connecting to `addr`, `None` if it failed right away.
+fn start_connecting(addr: SocketAddr) -> Option<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP)).ok()?;
+    socket.set_nonblocking(true).ok()?;
+    match socket.connect(&addr.into()) {
+        Ok(()) => Some(socket),
+        Err(e) if is_in_progress(&e) => Some(socket),
+        Err(_) => None,
+    }
+}
+
+/// Whether `e`, returned by the `connect` of a non-blocking socket, means the
+/// connection is in progress.
+fn is_in_progress(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::EINPROGRESS) {
+        return true;
+    }
+    e.kind() == io::ErrorKind::WouldBlock
+}
+
+/// Whether the connection of `socket` succeeded, failed, or is still in progress.
+fn state(socket: &Socket) -> Option<bool> {
+    match socket.take_error() {
+        Ok(None) => {}
+        Ok(Some(_)) | Err(_) => return Some(false),
+    }
+    match socket.peer_addr() {
+        Ok(_) => Some(true),
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => None,
+        Err(_) => Some(false),
+    }
+}
+
+/// `addrs` alternating between IPv6 and IPv4 addresses, starting with IPv6, as RFC 8305
+/// recommends.
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6());
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+    interleaved
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn resolver() -> Resolver {
+        Resolver {
+            hosts: HashMap::new(),
+            happy_eyeballs: true,
+            connect_timeout: Some(Duration::from_secs(10)),
+        }
+    }
+
+    /// An address nothing listens on.
+    fn closed(addr: &str) -> SocketAddr {
+        TcpListener::bind(addr).unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn first_address_to_connect_wins() {
+        let v6 = TcpListener::bind("[::1]:0").unwrap();
+        let v4 = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (v6, v4) = (v6.local_addr().unwrap(), v4.local_addr().unwrap());
+        assert_eq!(resolver().race("localhost", vec![v4, v6]), [v6, v4]);
+    }
+
+    #[test]
+    fn failed_attempts_start_the_next_one_right_away() {
+        let v6 = closed("[::1]:0");
+        let v4 = TcpListener::bind("127.0.0.1:0").unwrap();
+        let v4 = v4.local_addr().unwrap();
+        let started = Instant::now();
+        assert_eq!(resolver().race("localhost", vec![v6, v4]), [v4, v6]);
+        assert!(started.elapsed() < CONNECTION_ATTEMPT_DELAY);
+    }
+
+    #[test]
+    fn addresses_are_kept_when_every_attempt_fails() {
+        let (v6, v4) = (closed("[::1]:0"), closed("127.0.0.1:0"));
+        assert_eq!(resolver().race("localhost", vec![v6, v4]), [v6, v4]);
+    }
+
+    #[test]
+    fn attempts_in_progress_are_abandoned_once_one_connects() {
+        // Nothing answers in the discard prefix, when there is a route to it at all
+        let v6: SocketAddr = "[100::1]:9".parse().unwrap();
+        let v4 = TcpListener::bind("127.0.0.1:0").unwrap();
+        let v4 = v4.local_addr().unwrap();
+        let started = Instant::now();
+        assert_eq!(resolver().race("localhost", vec![v6, v4]), [v4, v6]);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
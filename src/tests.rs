@@ -0,0 +1,1448 @@
+//! Tests of the transport against servers on local ports.
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::Duration;
+
+use git2::build::RepoBuilder;
+use git2::transport::{Service, SmartSubtransport};
+use git2::{Cred, Error, ErrorClass, Repository, Signature};
+use url::Url;
+
+use crate::{
+    advertised_version, advertisement_start, extra_headers, git_error, host_header, is_media_type,
+    is_timeout, with_transport, FollowRedirects, UreqTransport, UreqTransportBuilder,
+};
+
+/// The object id the references of [`advertisement`] point to.
+const OID: &str = "0123456789abcdef0123456789abcdef01234567";
+
+/// A request received by a [`Server`].
+#[derive(Clone, Debug)]
+struct Received {
+    /// The number of the connection it came over, counting from 0.
+    connection: usize,
+    method: String,
+    /// The path of the request, with its query.
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Received {
+    /// The value of the header `name`, if it was sent.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+type Handler = Arc<dyn Fn(&Received) -> Vec<u8> + Send + Sync>;
+
+/// An HTTP/1.1 server answering the requests it receives with the raw responses
+/// of a handler, keeping connections open unless a response closes them.
+struct Server {
+    addr: SocketAddr,
+    scheme: &'static str,
+    received: Arc<Mutex<Vec<Received>>>,
+    /// The addresses the connections came from.
+    peers: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+/// A connection requests are read from and responses written to.
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Turns an accepted connection into the stream of the requests.
+type Accept = fn(TcpStream) -> Box<dyn Stream>;
+
+impl Server {
+    fn start(handler: impl Fn(&Received) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        Self::start_on("127.0.0.1:0", handler)
+    }
+
+    fn start_on(
+        addr: &str,
+        handler: impl Fn(&Received) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        Self::listen(addr, "http", false, |stream| Box::new(stream), handler)
+    }
+
+    /// Start a server closing each connection after its first response, without
+    /// saying so in the response.
+    fn start_closing(handler: impl Fn(&Received) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        Self::listen(
+            "127.0.0.1:0",
+            "http",
+            true,
+            |stream| Box::new(stream),
+            handler,
+        )
+    }
+
+    /// Start a server speaking TLS with the [`self_signed`] certificate.
+    #[cfg(feature = "rustls")]
+    fn start_tls(handler: impl Fn(&Received) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        Self::listen(
+            "127.0.0.1:0",
+            "https",
+            false,
+            |stream| {
+                let config = self_signed().1.clone();
+                let connection = rustls::ServerConnection::new(config).unwrap();
+                Box::new(rustls::StreamOwned::new(connection, stream))
+            },
+            handler,
+        )
+    }
+
+    fn listen(
+        addr: &str,
+        scheme: &'static str,
+        close: bool,
+        accept: Accept,
+        handler: impl Fn(&Received) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        let listener = TcpListener::bind(addr).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let peers = Arc::new(Mutex::new(Vec::new()));
+        let handler: Handler = Arc::new(handler);
+        let server = Server {
+            addr,
+            scheme,
+            received: received.clone(),
+            peers: peers.clone(),
+        };
+        thread::spawn(move || {
+            for (connection, stream) in listener.incoming().enumerate() {
+                let Ok(stream) = stream else {
+                    return;
+                };
+                peers.lock().unwrap().extend(stream.peer_addr());
+                let (handler, received) = (handler.clone(), received.clone());
+                thread::spawn(move || {
+                    serve(accept(stream), connection, close, &handler, &received)
+                });
+            }
+        });
+        server
+    }
+
+    /// The URL of `path` on the server.
+    fn url(&self, path: &str) -> String {
+        format!("{}://{}{}", self.scheme, self.addr, path)
+    }
+
+    /// The requests received so far.
+    fn received(&self) -> Vec<Received> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// The addresses the connections so far came from.
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.peers.lock().unwrap().clone()
+    }
+}
+
+fn serve(
+    stream: Box<dyn Stream>,
+    connection: usize,
+    close: bool,
+    handler: &Handler,
+    received: &Mutex<Vec<Received>>,
+) {
+    let mut reader = BufReader::new(stream);
+    while let Some(mut request) = read_request(&mut reader) {
+        request.connection = connection;
+        received.lock().unwrap().push(request.clone());
+        let response = handler(&request);
+        let stream = reader.get_mut();
+        let sent = stream.write_all(&response).and_then(|()| stream.flush());
+        if sent.is_err() || close || closes(&response) {
+            return;
+        }
+    }
+}
+
+/// Whether the connection ends with `response`: HTTP/1.0 ones and those with a
+/// `Connection: close` header.
+fn closes(response: &[u8]) -> bool {
+    let end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .unwrap_or(response.len());
+    let head = String::from_utf8_lossy(&response[..end]).to_ascii_lowercase();
+    head.starts_with("http/1.0") || head.contains("\r\nconnection: close")
+}
+
+fn read_request(reader: &mut impl BufRead) -> Option<Received> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).ok()? == 0 {
+        return None;
+    }
+    let mut parts = line.split_whitespace();
+    let (method, path) = (parts.next()?.to_string(), parts.next()?.to_string());
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    let mut request = Received {
+        connection: 0,
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+
+    if request.header("Transfer-Encoding") == Some("chunked") {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size).ok()?;
+            let size = usize::from_str_radix(size.trim(), 16).ok()?;
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).ok()?;
+            if size == 0 {
+                break;
+            }
+            request.body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(len) = request.header("Content-Length") {
+        request.body = vec![0; len.parse().ok()?];
+        reader.read_exact(&mut request.body).ok()?;
+    }
+    Some(request)
+}
+
+/// A response with `status`, `headers` and `body`, sent with a `Content-Length`
+/// unless the headers have a `Transfer-Encoding`.
+fn response(status: u16, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut head = format!("HTTP/1.1 {status} Status\r\n");
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if !headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Transfer-Encoding"))
+    {
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    head.push_str("\r\n");
+    let mut response = head.into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+fn pkt_line(line: &str) -> String {
+    format!("{:04x}{line}", line.len() + 4)
+}
+
+/// The version 0 advertisement of `service` for a repository with a single branch.
+fn advertisement(service: &str) -> Vec<u8> {
+    format!(
+        "{}0000{}0000",
+        pkt_line(&format!("# service=git-{service}\n")),
+        pkt_line(&format!("{OID} refs/heads/main\0ofs-delta\n"))
+    )
+    .into_bytes()
+}
+
+/// The response of a smart server to the advertisement request of `service`.
+fn advertisement_response(service: &str) -> Vec<u8> {
+    let content_type = format!("application/x-git-{service}-advertisement");
+    response(
+        200,
+        &[("Content-Type", &content_type)],
+        &advertisement(service),
+    )
+}
+
+/// The response of a smart server to the requests of `service` sending data.
+fn result_response(service: &str) -> Vec<u8> {
+    let content_type = format!("application/x-git-{service}-result");
+    response(
+        200,
+        &[("Content-Type", &content_type)],
+        pkt_line("NAK\n").as_bytes(),
+    )
+}
+
+/// Answer the advertisement and the upload-pack requests of every repository.
+fn smart(request: &Received) -> Vec<u8> {
+    match request.method.as_str() {
+        "GET" => advertisement_response("upload-pack"),
+        _ => result_response("upload-pack"),
+    }
+}
+
+/// Request the advertisement of upload-pack for `url`, as libgit2 does when fetching.
+fn ls_refs(transport: &UreqTransport, url: &str) -> Result<Vec<u8>, Error> {
+    let mut stream = transport.action(url, Service::UploadPackLs)?;
+    let mut advertisement = Vec::new();
+    stream.read_to_end(&mut advertisement).map_err(git_error)?;
+    Ok(advertisement)
+}
+
+/// Send `body` to upload-pack for `url`, as libgit2 does when negotiating, and
+/// return the result.
+fn upload_pack(transport: &UreqTransport, url: &str, body: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut stream = transport.action(url, Service::UploadPack)?;
+    stream.write_all(body).map_err(git_error)?;
+    let mut result = Vec::new();
+    stream.read_to_end(&mut result).map_err(git_error)?;
+    Ok(result)
+}
+
+#[test]
+fn accepted_authorization_is_sent_right_away_afterwards() {
+    let server = Server::start(|request| match request.header("Authorization") {
+        Some(_) => smart(request),
+        None => response(401, &[("WWW-Authenticate", "Basic realm=\"git\"")], b""),
+    });
+
+    let transport = UreqTransportBuilder::new()
+        .credentials(|_, _, _| Cred::userpass_plaintext("user", "secret"))
+        .build();
+    let url = server.url("/repo");
+    ls_refs(&transport, &url).unwrap();
+    upload_pack(&transport, &url, b"0000").unwrap();
+
+    let received = server.received();
+    assert_eq!(received.len(), 3);
+    assert_eq!(received[0].header("Authorization"), None);
+    assert!(received[1].header("Authorization").is_some());
+    assert_eq!(received[2].path, "/repo/git-upload-pack");
+    assert_eq!(received[2].body, b"0000");
+    assert_eq!(
+        received[2].header("Authorization"),
+        received[1].header("Authorization")
+    );
+}
+
+#[test]
+fn cross_origin_redirect_drops_authorization() {
+    let target = Server::start(smart);
+    // Another host name of the same address
+    let location = format!(
+        "http://localhost:{}/repo/info/refs?service=git-upload-pack",
+        target.addr.port()
+    );
+    let origin = Server::start(move |_| response(302, &[("Location", &location)], b""));
+
+    let transport = UreqTransportBuilder::new().build();
+    let url = origin
+        .url("/repo")
+        .replace("http://", "http://user:secret@");
+    assert_eq!(
+        ls_refs(&transport, &url).unwrap(),
+        advertisement("upload-pack")
+    );
+
+    let sent = &origin.received()[0];
+    assert!(sent.header("Authorization").is_some());
+    let redirected = &target.received()[0];
+    assert_eq!(redirected.header("Authorization"), None);
+}
+
+#[test]
+fn same_origin_redirect_keeps_authorization() {
+    let server = Server::start(|request| match request.path.starts_with("/old/") {
+        true => response(
+            301,
+            &[("Location", "/new/info/refs?service=git-upload-pack")],
+            b"",
+        ),
+        false => smart(request),
+    });
+
+    let transport = UreqTransportBuilder::new().build();
+    let url = server.url("/old").replace("http://", "http://user:secret@");
+    ls_refs(&transport, &url).unwrap();
+
+    let received = server.received();
+    assert_eq!(received[1].path, "/new/info/refs?service=git-upload-pack");
+    assert_eq!(
+        received[1].header("Authorization"),
+        received[0].header("Authorization")
+    );
+    assert!(received[1].header("Authorization").is_some());
+}
+
+#[test]
+fn accepted_authorization_is_only_reused_for_its_origin() {
+    let target = Server::start(smart);
+    let location = target.url("/repo/info/refs?service=git-upload-pack");
+    let origin = Server::start(move |request| match request.header("Authorization") {
+        Some(_) => response(302, &[("Location", &location)], b""),
+        None => response(401, &[("WWW-Authenticate", "Basic realm=\"git\"")], b""),
+    });
+
+    let transport = UreqTransportBuilder::new()
+        .credentials(|_, _, _| Cred::userpass_plaintext("user", "secret"))
+        .build();
+    let url = origin.url("/repo");
+    ls_refs(&transport, &url).unwrap();
+    upload_pack(&transport, &url, b"0000").unwrap();
+
+    // The target is on the same host as the origin, on another port
+    let received = target.received();
+    assert_eq!(received.len(), 2);
+    assert!(received
+        .iter()
+        .all(|request| request.header("Authorization").is_none()));
+}
+
+#[test]
+fn redirects_of_posts_keep_the_body_only_for_307_and_308() {
+    for status in [301, 302, 303, 307, 308] {
+        let server = Server::start(move |request| match request.path.as_str() {
+            "/old/git-upload-pack" => {
+                response(status, &[("Location", "/new/git-upload-pack")], b"")
+            }
+            _ => result_response("upload-pack"),
+        });
+
+        let transport = UreqTransportBuilder::new()
+            .follow_redirects(FollowRedirects::Always)
+            .build();
+        let body = pkt_line(&format!("want {OID}\n")) + "0000";
+        upload_pack(&transport, &server.url("/old"), body.as_bytes()).unwrap();
+
+        let received = server.received();
+        assert_eq!(received[0].body, body.as_bytes());
+        let redirected = &received[1];
+        assert_eq!(redirected.path, "/new/git-upload-pack");
+        match status {
+            307 | 308 => {
+                assert_eq!(redirected.method, "POST", "{status}");
+                assert_eq!(redirected.body, body.as_bytes(), "{status}");
+            }
+            _ => {
+                assert_eq!(redirected.method, "GET", "{status}");
+                assert!(redirected.body.is_empty(), "{status}");
+            }
+        }
+    }
+}
+
+#[test]
+fn upload_pack_request_has_its_content_type() {
+    let server = Server::start(smart);
+    let transport = UreqTransportBuilder::new().build();
+    upload_pack(&transport, &server.url("/repo"), b"0000").unwrap();
+
+    let request = &server.received()[0];
+    assert_eq!(request.path, "/repo/git-upload-pack");
+    assert_eq!(
+        request.header("Content-Type"),
+        Some("application/x-git-upload-pack-request")
+    );
+    assert_eq!(
+        request.header("Accept"),
+        Some("application/x-git-upload-pack-result")
+    );
+}
+
+#[test]
+fn media_types_ignore_case_and_parameters() {
+    let expected = "application/x-git-upload-pack-advertisement";
+    assert!(is_media_type(
+        "Application/X-Git-Upload-Pack-Advertisement",
+        expected
+    ));
+    assert!(is_media_type(
+        "application/x-git-upload-pack-advertisement; charset=utf-8",
+        expected
+    ));
+    assert!(is_media_type(
+        " APPLICATION/x-git-upload-pack-advertisement ;charset=UTF-8",
+        expected
+    ));
+    assert!(!is_media_type(
+        "application/x-git-upload-pack-result",
+        expected
+    ));
+    assert!(!is_media_type("text/plain; charset=utf-8", expected));
+}
+
+#[test]
+fn advertisement_with_charset_is_accepted() {
+    let server = Server::start(|_| {
+        response(
+            200,
+            &[(
+                "Content-Type",
+                "Application/x-git-upload-pack-advertisement; charset=utf-8",
+            )],
+            &advertisement("upload-pack"),
+        )
+    });
+    let transport = UreqTransportBuilder::new().build();
+    let refs = ls_refs(&transport, &server.url("/repo")).unwrap();
+    assert_eq!(refs, advertisement("upload-pack"));
+}
+
+/// A server answering advertisement requests in the version of the protocol they
+/// ask for, if any, like git's `http-backend`.
+fn versioned(request: &Received) -> Vec<u8> {
+    let version = request
+        .header("Git-Protocol")
+        .and_then(|protocol| protocol.strip_prefix("version="));
+    match (request.method.as_str(), version) {
+        ("GET", Some(version)) => {
+            let body = format!(
+                "{}0000{}{}0000",
+                pkt_line("# service=git-upload-pack\n"),
+                pkt_line(&format!("version {version}\n")),
+                pkt_line("ls-refs\n")
+            );
+            response(
+                200,
+                &[(
+                    "Content-Type",
+                    "application/x-git-upload-pack-advertisement",
+                )],
+                body.as_bytes(),
+            )
+        }
+        _ => smart(request),
+    }
+}
+
+#[test]
+fn protocol_version_header_follows_the_configured_version() {
+    for version in [None, Some(0), Some(1), Some(2)] {
+        let server = Server::start(versioned);
+        let builder = UreqTransportBuilder::new();
+        let builder = match version {
+            Some(version) => builder.protocol_version(version),
+            None => builder,
+        };
+        let transport = builder.build();
+        let url = server.url("/repo");
+        ls_refs(&transport, &url).unwrap();
+        upload_pack(&transport, &url, b"0000").unwrap();
+
+        let expected = match version {
+            None | Some(0) => None,
+            Some(version) => Some(format!("version={version}")),
+        };
+        let received = server.received();
+        assert_eq!(
+            received[0].header("Git-Protocol"),
+            expected.as_deref(),
+            "{version:?}"
+        );
+        // Answers in another version than 0 are requested again in version 0
+        for request in &received[1..] {
+            assert_eq!(
+                request.header("Git-Protocol"),
+                None,
+                "{} with {version:?}",
+                request.method
+            );
+        }
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn gzip_encoded_advertisement_is_decoded() {
+    for encoding in ["gzip", "x-gzip", "GZIP"] {
+        let server = Server::start(move |_| {
+            response(
+                200,
+                &[
+                    (
+                        "Content-Type",
+                        "application/x-git-upload-pack-advertisement",
+                    ),
+                    ("Content-Encoding", encoding),
+                ],
+                &gzip(&advertisement("upload-pack")),
+            )
+        });
+        let transport = UreqTransportBuilder::new().build();
+        let refs = ls_refs(&transport, &server.url("/repo")).unwrap();
+        assert_eq!(refs, advertisement("upload-pack"), "{encoding}");
+        assert_eq!(
+            server.received()[0].header("Accept-Encoding"),
+            crate::accept_encoding()
+        );
+    }
+}
+
+#[test]
+fn read_timeout_is_reported_as_a_timeout() {
+    let server = Server::start(|request| {
+        thread::sleep(Duration::from_secs(2));
+        smart(request)
+    });
+    let transport = UreqTransportBuilder::new()
+        .read_timeout(Duration::from_millis(100))
+        .build();
+    let e = ls_refs(&transport, &server.url("/repo")).unwrap_err();
+    assert_eq!(e.class(), ErrorClass::Net);
+    assert!(is_timeout(&e), "{e}");
+    assert!(!is_timeout(&Error::from_str("failed")));
+}
+
+#[test]
+fn slow_transfer_is_reported_as_a_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/repo", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        read_request(&mut reader).unwrap();
+        let stream = reader.get_mut();
+        let head = "HTTP/1.1 200 OK\r\n\
+            Content-Type: application/x-git-upload-pack-advertisement\r\n\
+            Content-Length: 1000\r\n\r\n";
+        stream.write_all(head.as_bytes()).unwrap();
+        // A byte every 100ms, below the limit but never idle for the read timeout
+        for _ in 0..50 {
+            thread::sleep(Duration::from_millis(100));
+            if stream.write_all(b"0").is_err() {
+                return;
+            }
+        }
+    });
+    let transport = UreqTransportBuilder::new()
+        .low_speed_limit(1000, Duration::from_millis(500))
+        .build();
+    let e = ls_refs(&transport, &url).unwrap_err();
+    assert_eq!(e.class(), ErrorClass::Net);
+    assert!(is_timeout(&e), "{e}");
+}
+
+#[test]
+fn host_header_has_the_port_unless_it_is_the_default() {
+    let host = |url: &str| host_header(&Url::parse(url).unwrap());
+    assert_eq!(host("https://example.com/repo.git"), "example.com");
+    assert_eq!(host("https://example.com:443/repo.git"), "example.com");
+    assert_eq!(host("http://example.com:80/repo.git"), "example.com");
+    assert_eq!(
+        host("https://example.com:8443/repo.git"),
+        "example.com:8443"
+    );
+    assert_eq!(host("http://example.com:443/repo.git"), "example.com:443");
+}
+
+#[test]
+fn host_header_of_a_server_on_another_port_has_the_port() {
+    let server = Server::start(smart);
+    let transport = UreqTransportBuilder::new().build();
+    ls_refs(&transport, &server.url("/repo")).unwrap();
+    let host = format!("127.0.0.1:{}", server.addr.port());
+    assert_eq!(server.received()[0].header("Host"), Some(host.as_str()));
+}
+
+#[test]
+fn trailing_slash_of_the_remote_is_not_doubled() {
+    let server = Server::start(smart);
+    let transport = UreqTransportBuilder::new().build();
+    ls_refs(&transport, &server.url("/repo/")).unwrap();
+    upload_pack(&transport, &server.url("/repo/"), b"0000").unwrap();
+    let paths: Vec<_> = server.received().into_iter().map(|r| r.path).collect();
+    assert_eq!(
+        paths,
+        [
+            "/repo/info/refs?service=git-upload-pack",
+            "/repo/git-upload-pack"
+        ]
+    );
+}
+
+#[test]
+fn query_of_the_remote_is_kept() {
+    let server = Server::start(smart);
+    let transport = UreqTransportBuilder::new().build();
+    let url = server.url("/repo?token=abc");
+    ls_refs(&transport, &url).unwrap();
+    upload_pack(&transport, &url, b"0000").unwrap();
+    let paths: Vec<_> = server.received().into_iter().map(|r| r.path).collect();
+    assert_eq!(
+        paths,
+        [
+            "/repo/info/refs?token=abc&service=git-upload-pack",
+            "/repo/git-upload-pack?token=abc"
+        ]
+    );
+}
+
+#[test]
+fn path_with_a_space_and_non_ascii_characters_is_percent_encoded() {
+    let server = Server::start(smart);
+    let transport = UreqTransportBuilder::new().build();
+    let url = format!("http://{}/my repo/dépôt", server.addr);
+    ls_refs(&transport, &url).unwrap();
+    upload_pack(&transport, &url, b"0000").unwrap();
+    let paths: Vec<_> = server.received().into_iter().map(|r| r.path).collect();
+    assert_eq!(
+        paths,
+        [
+            "/my%20repo/d%C3%A9p%C3%B4t/info/refs?service=git-upload-pack",
+            "/my%20repo/d%C3%A9p%C3%B4t/git-upload-pack"
+        ]
+    );
+}
+
+#[test]
+fn percent_encoded_path_is_not_encoded_twice() {
+    let server = Server::start(smart);
+    let transport = UreqTransportBuilder::new().build();
+    ls_refs(&transport, &server.url("/my%20repo")).unwrap();
+    assert_eq!(
+        server.received()[0].path,
+        "/my%20repo/info/refs?service=git-upload-pack"
+    );
+}
+
+/// A dumb server serving `info/refs` with the Content-Type `content_type`.
+#[cfg(feature = "dumb-http")]
+fn dumb(content_type: Option<&'static str>) -> Server {
+    Server::start(move |request| {
+        let refs = format!("{OID}\trefs/heads/main\n");
+        match request.path.split('?').next().unwrap() {
+            "/repo/info/refs" => match content_type {
+                Some(content_type) => {
+                    response(200, &[("Content-Type", content_type)], refs.as_bytes())
+                }
+                None => response(200, &[], refs.as_bytes()),
+            },
+            "/repo/HEAD" => response(200, &[], b"ref: refs/heads/main\n"),
+            _ => response(404, &[], b""),
+        }
+    })
+}
+
+#[cfg(feature = "dumb-http")]
+#[test]
+fn plain_or_untyped_info_refs_falls_back_to_dumb_http() {
+    for content_type in [None, Some("text/plain"), Some("Text/Plain; charset=utf-8")] {
+        let server = dumb(content_type);
+        let transport = UreqTransportBuilder::new().build();
+        let refs = ls_refs(&transport, &server.url("/repo")).unwrap();
+        let refs = String::from_utf8(refs).unwrap();
+        assert!(
+            refs.contains(&format!("{OID} refs/heads/main\n")),
+            "{content_type:?}: {refs}"
+        );
+    }
+}
+
+#[cfg(feature = "dumb-http")]
+#[test]
+fn info_refs_of_another_type_is_not_taken_for_dumb_http() {
+    let server = dumb(Some("application/json"));
+    let transport = UreqTransportBuilder::new().build();
+    let error = ls_refs(&transport, &server.url("/repo")).unwrap_err();
+    assert!(
+        error.message().contains("expected a Content-Type header"),
+        "{error}"
+    );
+    assert_eq!(server.received().len(), 1);
+}
+
+#[cfg(feature = "dumb-http")]
+#[test]
+fn files_of_dumb_servers_are_limited_to_max_response_bytes() {
+    let server = dumb(None);
+    let transport = UreqTransportBuilder::new().max_response_bytes(16).build();
+    let error = ls_refs(&transport, &server.url("/repo")).unwrap_err();
+    assert!(
+        error
+            .message()
+            .contains("larger than the limit of 16 bytes"),
+        "{error}"
+    );
+}
+
+#[cfg(all(feature = "dumb-http", feature = "deflate"))]
+#[test]
+fn files_of_dumb_servers_are_decoded() {
+    let server = Server::start(|request| match request.path.split('?').next().unwrap() {
+        "/repo/info/refs" => response(200, &[], format!("{OID}\trefs/heads/main\n").as_bytes()),
+        "/repo/HEAD" => {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(b"ref: refs/heads/main\n").unwrap();
+            let head = encoder.finish().unwrap();
+            response(200, &[("Content-Encoding", "deflate")], &head)
+        }
+        _ => response(404, &[], b""),
+    });
+    let transport = UreqTransportBuilder::new().build();
+    let refs = ls_refs(&transport, &server.url("/repo")).unwrap();
+    let refs = String::from_utf8_lossy(&refs);
+    assert!(refs.contains("symref=HEAD:refs/heads/main"), "{refs}");
+}
+
+/// Answer like [`smart`], with a `Connection: close` header.
+fn closing(request: &Received) -> Vec<u8> {
+    let mut response = String::from_utf8(smart(request)).unwrap();
+    response.insert_str(response.find("\r\n").unwrap() + 2, "Connection: close\r\n");
+    response.into_bytes()
+}
+
+/// Answer like [`smart`], with HTTP/1.0.
+fn http_1_0(request: &Received) -> Vec<u8> {
+    let response = smart(request);
+    [b"HTTP/1.0".as_slice(), &response[8..]].concat()
+}
+
+#[test]
+fn closed_connections_are_not_reused() {
+    for handler in [closing, http_1_0] {
+        let server = Server::start(handler);
+        let transport = UreqTransportBuilder::new().build();
+        ls_refs(&transport, &server.url("/repo")).unwrap();
+        upload_pack(&transport, &server.url("/repo"), b"0009done\n").unwrap();
+        let received = server.received();
+        let connections: Vec<_> = received.iter().map(|r| r.connection).collect();
+        assert_eq!(connections, [0, 1]);
+        assert_eq!(received[1].body, b"0009done\n");
+    }
+}
+
+#[test]
+fn post_is_sent_again_when_the_server_closed_the_pooled_connection() {
+    let server = Server::start_closing(smart);
+    let transport = UreqTransportBuilder::new().build();
+    ls_refs(&transport, &server.url("/repo")).unwrap();
+    // Let the server close the connection before it is reused
+    thread::sleep(Duration::from_millis(100));
+    let result = upload_pack(&transport, &server.url("/repo"), b"0009done\n").unwrap();
+    assert_eq!(result, pkt_line("NAK\n").as_bytes());
+    let posts: Vec<_> = server
+        .received()
+        .into_iter()
+        .filter(|r| r.method == "POST")
+        .collect();
+    assert_eq!(posts.last().unwrap().body, b"0009done\n");
+}
+
+#[test]
+fn unresolvable_host_is_reported_as_such() {
+    let transport = UreqTransportBuilder::new().build();
+    let error = ls_refs(&transport, "http://git.example.invalid/repo").unwrap_err();
+    assert_eq!(error.class(), git2::ErrorClass::Net);
+    assert!(
+        error
+            .message()
+            .starts_with("could not resolve host: git.example.invalid"),
+        "{error}"
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn connections_leave_from_the_local_address() {
+    // Linux routes the whole 127.0.0.0/8 block to the loopback interface
+    let local: IpAddr = "127.0.0.2".parse().unwrap();
+    let server = Server::start(smart);
+
+    let transport = UreqTransportBuilder::new().local_address(local).build();
+    let url = server.url("/repo");
+    ls_refs(&transport, &url).unwrap();
+    upload_pack(&transport, &url, b"0000").unwrap();
+
+    let peers = server.peers();
+    assert!(!peers.is_empty());
+    assert!(peers.iter().all(|peer| peer.ip() == local), "{peers:?}");
+    assert_eq!(server.received().len(), 2);
+}
+
+#[test]
+fn failing_to_bind_to_the_local_address_is_reported() {
+    let server = Server::start(smart);
+
+    // An address of TEST-NET-1, assigned to no interface
+    let transport = UreqTransportBuilder::new()
+        .local_address("192.0.2.1".parse().unwrap())
+        .build();
+    let error = ls_refs(&transport, &server.url("/repo")).unwrap_err();
+    assert_eq!(error.class(), ErrorClass::Net);
+    assert!(
+        error.message().starts_with("could not bind to 192.0.2.1: "),
+        "{error}"
+    );
+    assert!(server.peers().is_empty());
+}
+
+/// A directory with the bare repository `repo.git`, to serve with [`http_backend`].
+fn bare_repository() -> tempfile::TempDir {
+    let root = tempfile::tempdir().unwrap();
+    init_bare(&root.path().join("repo.git"), "hello\n");
+    root
+}
+
+/// Create a bare repository at `path`, with a commit on `main` of `hello.txt`
+/// with `contents`.
+fn init_bare(path: &Path, contents: &str) {
+    let repo = Repository::init_bare(path).unwrap();
+    let blob = repo.blob(contents.as_bytes()).unwrap();
+    let mut tree = repo.treebuilder(None).unwrap();
+    tree.insert("hello.txt", blob, 0o100644).unwrap();
+    let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+    let signature = Signature::now("a", "a@example.com").unwrap();
+    repo.commit(
+        Some("refs/heads/main"),
+        &signature,
+        &signature,
+        "hello",
+        &tree,
+        &[],
+    )
+    .unwrap();
+    repo.set_head("refs/heads/main").unwrap();
+}
+
+/// Answer `request` for the repositories of `root` with `git http-backend`.
+fn http_backend(root: &Path, request: &Received) -> Vec<u8> {
+    let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+    let mut backend = Command::new("git")
+        .arg("http-backend")
+        .env("GIT_PROJECT_ROOT", root)
+        .env("GIT_HTTP_EXPORT_ALL", "1")
+        .env("REQUEST_METHOD", &request.method)
+        .env("PATH_INFO", path)
+        .env("QUERY_STRING", query)
+        .env("CONTENT_TYPE", request.header("Content-Type").unwrap_or(""))
+        .env("CONTENT_LENGTH", request.body.len().to_string())
+        .env(
+            "HTTP_CONTENT_ENCODING",
+            request.header("Content-Encoding").unwrap_or(""),
+        )
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    backend
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(&request.body)
+        .unwrap();
+    let output = backend.wait_with_output().unwrap();
+
+    // A CGI response: headers, `Status` among them, then the body
+    let end = output
+        .stdout
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .unwrap();
+    let head = String::from_utf8_lossy(&output.stdout[..end]).into_owned();
+    let mut status = 200;
+    let mut headers = Vec::new();
+    for line in head.lines() {
+        let (name, value) = line.split_once(": ").unwrap();
+        match name {
+            "Status" => status = value[..3].parse().unwrap(),
+            _ => headers.push((name, value)),
+        }
+    }
+    response(status, &headers, &output.stdout[end + 4..])
+}
+
+/// Clone `url` with the transport registered with its defaults.
+/// Register the transport with its defaults, once for all tests.
+fn register() {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| unsafe { crate::register(None) }.unwrap());
+}
+
+/// Clone `url` with the registered transport.
+fn clone(url: &str) -> Result<tempfile::TempDir, Error> {
+    register();
+    let dir = tempfile::tempdir().unwrap();
+    RepoBuilder::new().clone(url, dir.path())?;
+    Ok(dir)
+}
+
+/// Check that `dir` has the commit of [`bare_repository`] checked out.
+fn assert_cloned(dir: &Path) {
+    assert_checked_out(dir, "hello\n");
+}
+
+/// Check that `dir` has the commit of [`init_bare`] with `contents` checked out.
+fn assert_checked_out(dir: &Path, contents: &str) {
+    let repo = Repository::open(dir).unwrap();
+    let head = repo.head().unwrap();
+    assert_eq!(head.name(), Some("refs/heads/main"));
+    assert_eq!(head.peel_to_commit().unwrap().message(), Some("hello"));
+    assert_eq!(
+        std::fs::read_to_string(dir.join("hello.txt")).unwrap(),
+        contents
+    );
+}
+
+#[test]
+fn ipv6_literals_keep_their_brackets_in_the_host_header() {
+    let host = |url: &str| host_header(&Url::parse(url).unwrap());
+    assert_eq!(host("https://[::1]/repo.git"), "[::1]");
+    assert_eq!(host("https://[::1]:443/repo.git"), "[::1]");
+    assert_eq!(
+        host("https://[2001:db8::1]:8443/repo.git"),
+        "[2001:db8::1]:8443"
+    );
+    assert_eq!(crate::tls::server_name("[::1]"), "::1");
+    assert_eq!(crate::tls::server_name("example.com"), "example.com");
+}
+
+#[test]
+fn requests_to_an_ipv6_literal() {
+    let server = Server::start_on("[::1]:0", smart);
+    let transport = UreqTransportBuilder::new().build();
+    let url = server.url("/repo");
+    assert!(url.starts_with("http://[::1]:"), "{url}");
+    ls_refs(&transport, &url).unwrap();
+    upload_pack(&transport, &url, b"0000").unwrap();
+
+    let received = server.received();
+    let host = format!("[::1]:{}", server.addr.port());
+    assert_eq!(received[0].path, "/repo/info/refs?service=git-upload-pack");
+    assert_eq!(received[0].header("Host"), Some(host.as_str()));
+    assert_eq!(received[1].path, "/repo/git-upload-pack");
+    assert_eq!(received[1].header("Host"), Some(host.as_str()));
+}
+
+#[test]
+fn clone_from_an_ipv6_literal() {
+    let root = bare_repository();
+    let path = root.path().to_path_buf();
+    let server = Server::start_on("[::1]:0", move |request| http_backend(&path, request));
+    let dir = clone(&server.url("/repo.git")).unwrap();
+    assert_cloned(dir.path());
+}
+
+#[test]
+fn small_bodies_are_sent_with_their_content_length() {
+    let server = Server::start(smart);
+    let transport = UreqTransportBuilder::new().build();
+    let body = b"0032want 0123456789abcdef0123456789abcdef01234567\n00000009done\n";
+    upload_pack(&transport, &server.url("/repo"), body).unwrap();
+    let sent = &server.received()[0];
+    assert_eq!(sent.header("Content-Length"), Some("63"));
+    assert_eq!(sent.header("Transfer-Encoding"), None);
+    assert_eq!(sent.body, body);
+}
+
+#[test]
+fn bodies_larger_than_the_post_buffer_are_chunked() {
+    let server = Server::start(smart);
+    let transport = UreqTransportBuilder::new().post_buffer(16).build();
+    let mut stream = transport
+        .action(&server.url("/repo"), Service::UploadPack)
+        .unwrap();
+    stream.write_all(&[b'a'; 10]).unwrap();
+    stream.write_all(&[b'b'; 10]).unwrap();
+    stream.read_to_end(&mut Vec::new()).unwrap();
+
+    let sent = &server.received()[0];
+    assert_eq!(sent.header("Content-Length"), None);
+    assert_eq!(sent.header("Transfer-Encoding"), Some("chunked"));
+    assert_eq!(sent.body, [[b'a'; 10], [b'b'; 10]].concat());
+}
+
+#[test]
+fn advertise_refs_requests_each_url_it_is_given() {
+    let first = Server::start(smart);
+    let second = Server::start(smart);
+    let transport = UreqTransportBuilder::new().build();
+    for server in [&first, &second] {
+        let refs = transport.advertise_refs(&server.url("/repo")).unwrap();
+        assert_eq!(refs, advertisement("upload-pack"));
+    }
+    assert_eq!(first.received().len(), 1);
+    assert_eq!(second.received().len(), 1);
+    assert_eq!(transport.resolved_url(), None);
+}
+
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+#[test]
+fn tls_connector_enables_tcp_keepalive() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    // The handshake fails once the server closes the connection, the keepalive is set before
+    thread::spawn(move || drop(listener.accept()));
+
+    let time = Duration::from_secs(42);
+    let connector = crate::tls::connector(&Default::default(), &Default::default(), Some(time))
+        .unwrap()
+        .unwrap();
+    let stream = TcpStream::connect(addr).unwrap();
+    let socket = stream.try_clone().unwrap();
+    let _ = ureq::TlsConnector::connect(&*connector, "localhost", Box::new(stream));
+
+    let socket = socket2::SockRef::from(&socket);
+    assert!(socket.keepalive().unwrap());
+    assert_eq!(socket.keepalive_time().unwrap(), time);
+}
+
+/// A self-signed certificate for `localhost` and `127.0.0.1`, in PEM, and the
+/// configuration of the servers presenting it.
+#[cfg(feature = "rustls")]
+fn self_signed() -> &'static (String, Arc<rustls::ServerConfig>) {
+    static SELF_SIGNED: std::sync::OnceLock<(String, Arc<rustls::ServerConfig>)> =
+        std::sync::OnceLock::new();
+    SELF_SIGNED.get_or_init(|| {
+        let names = ["localhost".to_string(), "127.0.0.1".to_string()];
+        let certified = rcgen::generate_simple_self_signed(names).unwrap();
+        let key = rustls_pki_types::PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![certified.cert.der().clone()], key.into())
+            .unwrap();
+        (certified.cert.pem(), Arc::new(config))
+    })
+}
+
+#[cfg(feature = "rustls")]
+#[test]
+fn self_signed_certificate_is_reported_as_untrusted() {
+    let server = Server::start_tls(smart);
+    let transport = UreqTransportBuilder::new().build();
+    let error = ls_refs(&transport, &server.url("/repo")).unwrap_err();
+    assert_eq!(error.code(), git2::ErrorCode::Certificate);
+    assert_eq!(error.class(), git2::ErrorClass::Ssl);
+    assert!(
+        error.message().starts_with(&format!(
+            "the TLS certificate of {} could not be verified",
+            server.addr.ip()
+        )),
+        "{error}"
+    );
+    assert!(server.received().is_empty());
+}
+
+#[cfg(feature = "rustls")]
+#[test]
+fn self_signed_certificate_is_accepted_once_trusted() {
+    let server = Server::start_tls(smart);
+    let ca_info = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(ca_info.path(), &self_signed().0).unwrap();
+    for builder in [
+        UreqTransportBuilder::new().ssl_ca_info(ca_info.path()),
+        UreqTransportBuilder::new().danger_accept_invalid_certs(true),
+    ] {
+        let transport = builder.build();
+        let refs = ls_refs(&transport, &server.url("/repo")).unwrap();
+        assert_eq!(refs, advertisement("upload-pack"));
+    }
+}
+
+#[test]
+fn concurrent_clones_of_different_repositories() {
+    let root = tempfile::tempdir().unwrap();
+    for n in 0..4 {
+        init_bare(&root.path().join(format!("repo{n}.git")), &format!("{n}\n"));
+    }
+    let path = root.path().to_path_buf();
+    let server = Server::start(move |request| http_backend(&path, request));
+    // Half of the clones are redirected from an alias, which mustn't change the URL
+    // of the others
+    let target = server.url("");
+    let aliases = Server::start(move |request| {
+        let location = format!("{target}{}", request.path.replacen("/alias", "/repo", 1));
+        response(301, &[("Location", &location)], b"")
+    });
+
+    let clones: Vec<_> = (0..16)
+        .map(|i| {
+            let n = i % 4;
+            let url = match i % 8 < 4 {
+                true => server.url(&format!("/repo{n}.git")),
+                false => aliases.url(&format!("/alias{n}.git")),
+            };
+            thread::spawn(move || {
+                let dir = clone(&url).unwrap();
+                assert_checked_out(dir.path(), &format!("{n}\n"));
+            })
+        })
+        .collect();
+    for clone in clones {
+        clone.join().unwrap();
+    }
+    assert_eq!(
+        aliases
+            .received()
+            .iter()
+            .filter(|r| r.method == "POST")
+            .count(),
+        0
+    );
+}
+
+#[test]
+fn empty_extra_header_entry_resets_every_header_before_it() {
+    let builder = [("X-Builder".to_string(), "1".to_string())];
+    let headers = |list: &[(&str, &str)]| -> Vec<(String, String)> {
+        list.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    };
+
+    assert_eq!(
+        extra_headers(&["X-A: 1", "x-a: 2", "X-B: 3"], &builder),
+        headers(&[("X-A", "1, 2"), ("X-B", "3"), ("X-Builder", "1")])
+    );
+    assert_eq!(
+        extra_headers(&["X-A: 1", "", "X-B: 2"], &builder),
+        headers(&[("X-B", "2")])
+    );
+    assert_eq!(extra_headers(&[""], &builder), headers(&[]));
+    assert_eq!(
+        extra_headers(&["X-Builder: config"], &builder),
+        headers(&[("X-Builder", "1")])
+    );
+}
+
+#[test]
+fn advertised_version_is_read_after_the_service_header() {
+    assert_eq!(advertised_version(&advertisement("upload-pack")), 0);
+    for version in [1, 2] {
+        let advertisement = format!(
+            "{}0000{}",
+            pkt_line("# service=git-upload-pack\n"),
+            pkt_line(&format!("version {version}\n"))
+        );
+        assert_eq!(advertised_version(advertisement.as_bytes()), version);
+        let advertisement = pkt_line(&format!("version {version}\n"));
+        assert_eq!(advertised_version(advertisement.as_bytes()), version);
+    }
+    assert_eq!(advertised_version(b"00"), 0);
+}
+
+/// A reader returning a single byte at a time.
+struct OneByte<R>(R);
+
+impl<R: Read> Read for OneByte<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(1);
+        self.0.read(&mut buf[..len])
+    }
+}
+
+#[test]
+fn advertisement_start_is_read_across_short_reads() {
+    let advertisement = format!(
+        "{}0000{}{}0000",
+        pkt_line("# service=git-upload-pack\n"),
+        pkt_line("version 2\n"),
+        pkt_line("ls-refs\n")
+    );
+    let mut reader = OneByte(advertisement.as_bytes());
+    let start = advertisement_start(&mut reader).unwrap();
+    assert_eq!(advertised_version(&start), 2);
+
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert_eq!([start, rest].concat(), advertisement.as_bytes());
+}
+
+#[test]
+fn downgraded_protocol_version_continues_in_version_0() {
+    // `smart` always answers in version 0
+    let server = Server::start(smart);
+    let transport = UreqTransportBuilder::new().protocol_version(2).build();
+    let url = server.url("/repo");
+    assert_eq!(
+        ls_refs(&transport, &url).unwrap(),
+        advertisement("upload-pack")
+    );
+    upload_pack(&transport, &url, b"0000").unwrap();
+
+    let received = server.received();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0].header("Git-Protocol"), Some("version=2"));
+    assert_eq!(received[1].header("Git-Protocol"), None);
+}
+
+#[test]
+fn honored_protocol_version_is_requested_again_in_version_0() {
+    let server = Server::start(versioned);
+    let transport = UreqTransportBuilder::new().protocol_version(2).build();
+    let url = server.url("/repo");
+    assert_eq!(
+        ls_refs(&transport, &url).unwrap(),
+        advertisement("upload-pack")
+    );
+    upload_pack(&transport, &url, b"0000").unwrap();
+    // Later advertisements are requested in version 0 right away
+    ls_refs(&transport, &url).unwrap();
+
+    let received = server.received();
+    let versions: Vec<_> = received
+        .iter()
+        .map(|request| (request.method.as_str(), request.header("Git-Protocol")))
+        .collect();
+    assert_eq!(
+        versions,
+        [
+            ("GET", Some("version=2")),
+            ("GET", None),
+            ("POST", None),
+            ("GET", None)
+        ]
+    );
+}
+
+#[test]
+fn advertisements_in_version_2_without_asking_fail() {
+    let server = Server::start(|request| {
+        let mut request = request.clone();
+        request
+            .headers
+            .push(("Git-Protocol".to_string(), "version=2".to_string()));
+        versioned(&request)
+    });
+    let transport = UreqTransportBuilder::new().protocol_version(2).build();
+    let error = ls_refs(&transport, &server.url("/repo")).unwrap_err();
+    assert!(
+        error.message().contains("answered in protocol version 2"),
+        "{error}"
+    );
+    assert_eq!(server.received().len(), 2);
+}
+
+#[test]
+fn cookies_set_by_the_advertisement_are_sent_with_the_post() {
+    let server = Server::start(|request| match request.method.as_str() {
+        "GET" => {
+            let mut response = String::from_utf8(advertisement_response("upload-pack")).unwrap();
+            let end = response.find("\r\n").unwrap() + 2;
+            response.insert_str(end, "Set-Cookie: session=abc; Path=/; HttpOnly\r\n");
+            response.into_bytes()
+        }
+        _ => smart(request),
+    });
+    let url = server.url("/repo");
+    let transport = UreqTransportBuilder::new().build();
+    ls_refs(&transport, &url).unwrap();
+    upload_pack(&transport, &url, b"0000").unwrap();
+    // The cookies of an operation aren't sent by the transports of other operations
+    let other = UreqTransportBuilder::new().build();
+    upload_pack(&other, &url, b"0000").unwrap();
+
+    let received = server.received();
+    assert_eq!(received[0].header("Cookie"), None);
+    assert_eq!(received[1].header("Cookie"), Some("session=abc"));
+    assert_eq!(received[2].header("Cookie"), None);
+}
+
+/// `data` as a single chunk, followed by the last one.
+fn chunked(data: &[u8]) -> Vec<u8> {
+    [
+        format!("{:x}\r\n", data.len()).as_bytes(),
+        data,
+        b"\r\n0\r\n\r\n",
+    ]
+    .concat()
+}
+
+/// An advertisement response with the status line `status` and the header
+/// `Transfer-Encoding: encoding`, of `body`.
+fn transfer_encoded(status: &str, encoding: &str, body: &[u8]) -> Vec<u8> {
+    let head = format!(
+        "{status}\r\nContent-Type: application/x-git-upload-pack-advertisement\r\n\
+         Transfer-Encoding: {encoding}\r\n\r\n"
+    );
+    [head.as_bytes(), body].concat()
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn gzip_transfer_coding_is_decoded_when_chunked_or_over_http_1_0() {
+    let compressed = gzip(&advertisement("upload-pack"));
+    for response in [
+        transfer_encoded("HTTP/1.1 200 OK", "gzip, chunked", &chunked(&compressed)),
+        transfer_encoded("HTTP/1.0 200 OK", "gzip", &compressed),
+    ] {
+        let server = Server::start(move |_| response.clone());
+        let transport = UreqTransportBuilder::new().build();
+        let refs = ls_refs(&transport, &server.url("/repo")).unwrap();
+        assert_eq!(refs, advertisement("upload-pack"));
+    }
+}
+
+#[test]
+fn bare_transfer_coding_over_http_1_1_is_refused() {
+    let server = Server::start(|_| transfer_encoded("HTTP/1.1 200 OK", "gzip", b"not chunked"));
+    let transport = UreqTransportBuilder::new().build();
+    let error = ls_refs(&transport, &server.url("/repo")).unwrap_err();
+    assert!(
+        error
+            .message()
+            .contains("unsupported Transfer-Encoding `gzip`: over HTTP/1.1, only bodies"),
+        "{error}"
+    );
+}
+
+#[test]
+fn unknown_transfer_coding_is_refused() {
+    let body = chunked(&advertisement("upload-pack"));
+    let server = Server::start(move |_| transfer_encoded("HTTP/1.1 200 OK", "br, chunked", &body));
+    let transport = UreqTransportBuilder::new().build();
+    let error = ls_refs(&transport, &server.url("/repo")).unwrap_err();
+    assert!(
+        error
+            .message()
+            .contains("unsupported Transfer-Encoding `br`"),
+        "{error}"
+    );
+}
+
+#[test]
+fn with_transport_configures_the_operations_on_the_thread_of_the_closure() {
+    let root = bare_repository();
+    let path = root.path().to_path_buf();
+    let server = Server::start(move |request| http_backend(&path, request));
+    let url = server.url("/repo.git");
+
+    register();
+    let builder = UreqTransportBuilder::new().user_agent("scoped");
+    let (dir, other_thread) = with_transport(builder, || {
+        let dir = clone(&url).unwrap();
+        let url = url.clone();
+        let other_thread = std::thread::spawn(move || clone(&url).unwrap());
+        (dir, other_thread.join().unwrap())
+    })
+    .unwrap();
+    assert_cloned(dir.path());
+    assert_cloned(other_thread.path());
+
+    let received = server.received();
+    assert_eq!(received[0].header("User-Agent"), Some("scoped"));
+    assert_ne!(
+        received.last().unwrap().header("User-Agent"),
+        Some("scoped")
+    );
+}